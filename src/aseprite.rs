@@ -0,0 +1,476 @@
+use std::{collections::BTreeSet, fs, path::Path};
+
+use crate::{color::{Color, BlendMode}, sheet::FrameTag};
+
+const HEADER_MAGIC : u16 = 0xA5E0;
+const FRAME_MAGIC : u16 = 0xF1FA;
+
+const CHUNK_OLD_PALETTE : u16 = 0x0004;
+const CHUNK_LAYER : u16 = 0x2004;
+const CHUNK_CEL : u16 = 0x2005;
+const CHUNK_TAGS : u16 = 0x2018;
+const CHUNK_PALETTE : u16 = 0x2019;
+const CHUNK_TILESET : u16 = 0x2023;
+
+const LAYER_VISIBLE : u16 = 1;
+const LAYER_BACKGROUND : u16 = 8;
+const LAYER_REFERENCE : u16 = 64;
+const LAYER_GROUP : u16 = 1;
+const LAYER_TILEMAP : u16 = 2;
+
+/// Aseprite blend modes which have an equivalent `BlendMode`, the others being composed as normal.
+const BLEND_MODES : & 'static [(u16, BlendMode)] = &[
+    (0, BlendMode::Normal),
+    (1, BlendMode::Multiply),
+    (2, BlendMode::Screen),
+    (3, BlendMode::Overlay),
+    (16, BlendMode::Add),
+];
+
+/// A sprite read from an Aseprite file, with its layers composed in each frame.
+pub struct Sprite {
+    pub size:(usize, usize),
+    /// Pixels of each frame, row by row, and its duration in milliseconds.
+    pub frames:Vec<(Vec<Color>, u32)>,
+    pub palette:Vec<Color>,
+    pub tags:Vec<FrameTag>,
+    /// Whether the sprite is in indexed color mode.
+    pub indexed:bool,
+    /// Features of the file which were ignored or approximated.
+    pub warnings:Vec<String>,
+}
+
+struct Reader<'a> {
+    data:&'a [u8],
+    pos:usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n:usize) -> Result<&'a [u8], String> {
+        let ret = self.data.get(self.pos..self.pos + n).ok_or_else(|| String::from("unexpected end of file"))?;
+        self.pos += n;
+        Ok(ret)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn i16(&mut self) -> Result<i16, String> {
+        Ok(self.u16()? as i16)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.u16()? as usize;
+        Ok(String::from_utf8_lossy(self.bytes(len)?).into_owned())
+    }
+}
+
+struct Layer {
+    visible:bool,
+    background:bool,
+    opacity:u8,
+    blend:BlendMode,
+}
+
+enum CelData {
+    /// Size and pixels, in the color depth of the file.
+    Image((usize, usize), Vec<u8>),
+    /// Same cel as in another frame.
+    Linked(usize),
+}
+
+struct Cel {
+    layer:usize,
+    position:(i32, i32),
+    opacity:u8,
+    z_index:i16,
+    data:CelData,
+}
+
+pub fn load<P:AsRef<Path>>(path:P) -> Result<Sprite, String> {
+    let path = path.as_ref();
+    let data = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    parse(&data).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Parse an Aseprite file, as described in aseprite/docs/ase-file-specs.md.
+pub fn parse(data:&[u8]) -> Result<Sprite, String> {
+    let mut r = Reader { data, pos:0 };
+    let mut warnings = BTreeSet::new();
+
+    r.u32()?;
+    if r.u16()? != HEADER_MAGIC {
+        return Err(String::from("not an Aseprite file"))
+    }
+    let num_frames = r.u16()? as usize;
+    let size = (r.u16()? as usize, r.u16()? as usize);
+    let depth = r.u16()?;
+    let layer_opacity_valid = r.u32()? & 1 != 0;
+    r.bytes(10)?;
+    let transparent_index = r.u8()?;
+    r.pos = 128;
+
+    if ![8, 16, 32].contains(&depth) {
+        return Err(format!("unsupported color depth: {}", depth))
+    }
+
+    let mut layers = Vec::new();
+    // visibility of the group at each level of the layer hierarchy
+    let mut groups : Vec<bool> = Vec::new();
+    let mut frames = Vec::with_capacity(num_frames);
+    let mut palette = Vec::new();
+    let mut tags = Vec::new();
+
+    for _ in 0..num_frames {
+        let frame_start = r.pos;
+        let frame_len = r.u32()? as usize;
+        if r.u16()? != FRAME_MAGIC {
+            return Err(String::from("invalid frame header"))
+        }
+        let old_chunks = r.u16()? as usize;
+        let duration = r.u16()? as u32;
+        r.bytes(2)?;
+        let chunks = match r.u32()? as usize {
+            0 => old_chunks,
+            n => n,
+        };
+
+        let mut cels = Vec::new();
+        for _ in 0..chunks {
+            let chunk_start = r.pos;
+            let chunk_len = r.u32()? as usize;
+            let kind = r.u16()?;
+
+            match kind {
+                CHUNK_LAYER => {
+                    let flags = r.u16()?;
+                    let layer_type = r.u16()?;
+                    let level = r.u16()? as usize;
+                    r.bytes(4)?;
+                    let blend = r.u16()?;
+                    let opacity = r.u8()?;
+                    r.bytes(3)?;
+                    let name = r.string()?;
+
+                    let visible = flags & LAYER_VISIBLE != 0 && flags & LAYER_REFERENCE == 0
+                        && groups.get(..level).map_or(true, |g| g.iter().all(|v| *v));
+                    groups.truncate(level);
+                    groups.push(visible);
+
+                    if layer_type == LAYER_TILEMAP {
+                        warnings.insert(format!("tilemap layer {} ignored", name));
+                    }
+                    let blend = BLEND_MODES.iter().find(|(b, _)| *b == blend).map(|(_, mode)| *mode);
+                    if blend.is_none() && visible {
+                        warnings.insert(format!("blend mode of layer {} unsupported", name));
+                    }
+
+                    layers.push(Layer {
+                        // groups and tilemaps have no pixels of their own
+                        visible:visible && layer_type != LAYER_GROUP && layer_type != LAYER_TILEMAP,
+                        background:flags & LAYER_BACKGROUND != 0,
+                        opacity:if layer_opacity_valid { opacity } else { 255 },
+                        blend:blend.unwrap_or(BlendMode::Normal),
+                    });
+                },
+                CHUNK_CEL => {
+                    let layer = r.u16()? as usize;
+                    let position = (r.i16()? as i32, r.i16()? as i32);
+                    let opacity = r.u8()?;
+                    let cel_type = r.u16()?;
+                    let z_index = r.i16()?;
+                    r.bytes(5)?;
+
+                    let data = match cel_type {
+                        0 | 2 => {
+                            let cel_size = (r.u16()? as usize, r.u16()? as usize);
+                            let len = (chunk_start + chunk_len).checked_sub(r.pos)
+                                .ok_or_else(|| String::from("invalid chunk length"))?;
+                            let rest = r.bytes(len)?;
+                            let pixels = if cel_type == 0 {
+                                rest.to_vec()
+                            } else {
+                                miniz_oxide::inflate::decompress_to_vec_zlib(rest)
+                                    .map_err(|e| format!("invalid compressed cel: {:?}", e))?
+                            };
+
+                            if pixels.len() < cel_size.0 * cel_size.1 * depth as usize / 8 {
+                                return Err(String::from("cel too small for its size"))
+                            }
+                            Some(CelData::Image(cel_size, pixels))
+                        },
+                        1 => Some(CelData::Linked(r.u16()? as usize)),
+                        _ => {
+                            warnings.insert(String::from("tilemap cels ignored"));
+                            None
+                        },
+                    };
+
+                    if let Some(data) = data {
+                        cels.push(Cel { layer, position, opacity, z_index, data });
+                    }
+                },
+                CHUNK_TAGS => {
+                    let n = r.u16()?;
+                    r.bytes(8)?;
+                    for _ in 0..n {
+                        let (from, to) = (r.u16()? as usize, r.u16()? as usize);
+                        let direction = match r.u8()? {
+                            1 => "reverse",
+                            2 | 3 => "pingpong",
+                            _ => "forward",
+                        };
+                        r.bytes(12)?;
+                        let name = r.string()?;
+                        tags.push(FrameTag { name, from, to, direction:String::from(direction) });
+                    }
+                },
+                CHUNK_PALETTE => {
+                    let len = r.u32()? as usize;
+                    let (first, last) = (r.u32()? as usize, r.u32()? as usize);
+                    r.bytes(8)?;
+                    palette.resize(len, Color::rgba(0, 0, 0, 0));
+
+                    for i in first..=last {
+                        let flags = r.u16()?;
+                        let color = Color::rgba(r.u8()?, r.u8()?, r.u8()?, r.u8()?);
+                        if flags & 1 != 0 {
+                            r.string()?;
+                        }
+                        if let Some(entry) = palette.get_mut(i) {
+                            *entry = color;
+                        }
+                    }
+                },
+                // only used when there is no new palette chunk
+                CHUNK_OLD_PALETTE if palette.is_empty() => {
+                    let mut i = 0;
+                    for _ in 0..r.u16()? {
+                        i += r.u8()? as usize;
+                        let n = match r.u8()? { 0 => 256, n => n as usize };
+                        for _ in 0..n {
+                            let color = Color::rgb(r.u8()?, r.u8()?, r.u8()?);
+                            if palette.len() <= i {
+                                palette.resize(i + 1, Color::rgba(0, 0, 0, 0));
+                            }
+                            palette[i] = color;
+                            i += 1;
+                        }
+                    }
+                },
+                CHUNK_TILESET => {
+                    warnings.insert(String::from("tilesets ignored"));
+                },
+                _ => (),
+            }
+
+            r.pos = chunk_start + chunk_len;
+        }
+
+        frames.push((cels, duration));
+        r.pos = frame_start + frame_len;
+    }
+
+    // pixels are decoded once the palette is known
+    let color_of = |p:&[u8], background:bool| match depth {
+        32 => Color::rgba(p[0], p[1], p[2], p[3]),
+        16 => Color::rgba(p[0], p[0], p[0], p[1]),
+        _ if p[0] == transparent_index && !background => Color::rgba(0, 0, 0, 0),
+        _ => palette.get(p[0] as usize).copied().unwrap_or(Color::rgba(0, 0, 0, 0)),
+    };
+
+    let (w, h) = size;
+    let mut composed = Vec::with_capacity(frames.len());
+    for (cels, duration) in frames.iter() {
+        let mut order : Vec<&Cel> = cels.iter().collect();
+        order.sort_by_key(|c| (c.layer as i32 + c.z_index as i32, c.z_index));
+
+        let mut pixels = vec![Color::rgba(0, 0, 0, 0); w * h];
+        for cel in order {
+            let layer = match layers.get(cel.layer) {
+                Some(layer) if layer.visible => layer,
+                _ => continue,
+            };
+
+            // a linked cel takes the pixels of the cel of the same layer in the other frame
+            let data = match &cel.data {
+                CelData::Linked(frame) => frames
+                    .get(*frame)
+                    .and_then(|(cels, _)| cels.iter().find(|c| c.layer == cel.layer))
+                    .map(|c| &c.data),
+                data => Some(data),
+            };
+            let (cel_w, cel_h, bytes) = match data {
+                Some(CelData::Image((cel_w, cel_h), bytes)) => (*cel_w, *cel_h, bytes),
+                _ => continue,
+            };
+
+            let bpp = depth as usize / 8;
+            let opacity = layer.opacity as u32 * cel.opacity as u32;
+            for cy in 0..cel_h {
+                for cx in 0..cel_w {
+                    let (x, y) = (cel.position.0 + cx as i32, cel.position.1 + cy as i32);
+                    if x < 0 || y < 0 || x >= w as i32 || y >= h as i32 {
+                        continue
+                    }
+
+                    let i = (cy * cel_w + cx) * bpp;
+                    let mut color = color_of(&bytes[i..i + bpp], layer.background);
+                    color.a = (color.a as u32 * opacity / (255 * 255)) as u8;
+
+                    let dst = &mut pixels[y as usize * w + x as usize];
+                    *dst = layer.blend.blend(color, *dst);
+                }
+            }
+        }
+
+        composed.push((pixels, *duration));
+    }
+
+    Ok(Sprite {
+        size,
+        frames:composed,
+        palette,
+        tags,
+        indexed:depth == 8,
+        warnings:warnings.into_iter().collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(s:&str) -> Vec<u8> {
+        let mut ret = (s.len() as u16).to_le_bytes().to_vec();
+        ret.extend_from_slice(s.as_bytes());
+        ret
+    }
+
+    fn words(values:&[u16]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_le_bytes().to_vec()).collect()
+    }
+
+    fn chunk(kind:u16, data:&[u8]) -> Vec<u8> {
+        let mut ret = ((data.len() + 6) as u32).to_le_bytes().to_vec();
+        ret.extend(words(&[kind]));
+        ret.extend_from_slice(data);
+        ret
+    }
+
+    fn frame(chunks:&[Vec<u8>], duration:u16) -> Vec<u8> {
+        let body = chunks.concat();
+        let mut ret = ((body.len() + 16) as u32).to_le_bytes().to_vec();
+        ret.extend(words(&[FRAME_MAGIC, chunks.len() as u16, duration, 0]));
+        ret.extend_from_slice(&(chunks.len() as u32).to_le_bytes());
+        ret.extend(body);
+        ret
+    }
+
+    /// A 32 bits per pixel file, with valid layer opacities.
+    fn file((w, h):(u16, u16), frames:&[Vec<u8>]) -> Vec<u8> {
+        let body = frames.concat();
+        let mut ret = ((body.len() + 128) as u32).to_le_bytes().to_vec();
+        ret.extend(words(&[HEADER_MAGIC, frames.len() as u16, w, h, 32]));
+        ret.extend_from_slice(&1u32.to_le_bytes());
+        ret.resize(128, 0);
+        ret.extend(body);
+        ret
+    }
+
+    fn layer(name:&str, opacity:u8) -> Vec<u8> {
+        let mut data = words(&[LAYER_VISIBLE, 0, 0, 0, 0, 0]);
+        data.extend_from_slice(&[opacity, 0, 0, 0]);
+        data.extend(string(name));
+        chunk(CHUNK_LAYER, &data)
+    }
+
+    /// Opaque cel of the given type, followed by its content.
+    fn cel(layer:u16, (x, y):(i16, i16), cel_type:u16, content:&[u8]) -> Vec<u8> {
+        let mut data = words(&[layer, x as u16, y as u16]);
+        data.push(255);
+        data.extend(words(&[cel_type]));
+        // z-index and reserved bytes
+        data.extend_from_slice(&[0; 7]);
+        data.extend_from_slice(content);
+        chunk(CHUNK_CEL, &data)
+    }
+
+    fn image((w, h):(u16, u16), pixels:&[Color]) -> Vec<u8> {
+        let mut ret = words(&[w, h]);
+        ret.extend(pixels.iter().flat_map(|c| vec![c.r, c.g, c.b, c.a]));
+        ret
+    }
+
+    fn tag(name:&str, from:u16, to:u16, direction:u8) -> Vec<u8> {
+        let mut ret = words(&[from, to]);
+        ret.push(direction);
+        ret.extend_from_slice(&[0; 12]);
+        ret.extend(string(name));
+        ret
+    }
+
+    #[test]
+    fn layers_linked_cels_and_tags() {
+        let (red, blue, none) = (Color::rgb(255, 0, 0), Color::rgb(0, 0, 255), Color::rgba(0, 0, 0, 0));
+
+        let mut tags = words(&[1, 0, 0, 0, 0]);
+        tags.extend(tag("walk", 0, 1, 2));
+        let compressed = miniz_oxide::deflate::compress_to_vec_zlib(&image((2, 1), &[blue, blue])[4..], 6);
+        let mut top = words(&[2, 1]);
+        top.extend(compressed);
+
+        let data = file((3, 2), &[
+            frame(&[
+                layer("bg", 255),
+                layer("top", 128),
+                chunk(CHUNK_TAGS, &tags),
+                cel(0, (0, 0), 0, &image((2, 2), &[red; 4])),
+                cel(1, (1, 1), 2, &top),
+            ], 100),
+            // the cel of the first layer is the one of the first frame, moved right
+            frame(&[cel(0, (1, 0), 1, &words(&[0]))], 250),
+        ]);
+
+        let sprite = parse(&data).unwrap();
+        assert_eq!(sprite.size, (3, 2));
+        assert!(!sprite.indexed);
+        assert!(sprite.warnings.is_empty());
+
+        let half_blue = Color::rgba(0, 0, 255, 128);
+        assert_eq!(sprite.frames[0].0, [
+            red, red, none,
+            red, BlendMode::Normal.blend(half_blue, red), BlendMode::Normal.blend(half_blue, none),
+        ]);
+        assert_eq!(sprite.frames[0].1, 100);
+        assert_eq!(sprite.frames[1].0, [none, red, red, none, red, red]);
+        assert_eq!(sprite.frames[1].1, 250);
+
+        assert_eq!(sprite.tags.len(), 1);
+        let walk = &sprite.tags[0];
+        assert_eq!((walk.name.as_str(), walk.from, walk.to, walk.direction.as_str()), ("walk", 0, 1, "pingpong"));
+    }
+
+    #[test]
+    fn invalid_files() {
+        assert_eq!(parse(&[0; 4]).err(), Some(String::from("unexpected end of file")));
+        assert_eq!(parse(&[0; 128]).err(), Some(String::from("not an Aseprite file")));
+
+        // a cel chunk shorter than its own header
+        let mut bad = cel(0, (0, 0), 0, &image((1, 1), &[Color::rgb(0, 0, 0)]));
+        bad[..4].copy_from_slice(&10u32.to_le_bytes());
+        let data = file((1, 1), &[frame(&[layer("bg", 255), bad], 100)]);
+        assert_eq!(parse(&data).err(), Some(String::from("invalid chunk length")));
+    }
+}
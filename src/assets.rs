@@ -0,0 +1,104 @@
+use std::{borrow::Cow, env, fs, path::PathBuf, time::{Duration, Instant, SystemTime}};
+
+/// Environment variable naming a directory whose files take precedence over the embedded ones,
+/// e.g. the repository root while working on the shaders.
+const OVERRIDE_DIR_VAR : &str = "PIXY_ASSETS";
+
+/// Files compiled into the binary, by path relative to the repository root.
+const EMBEDDED : & 'static [(& 'static str, & 'static [u8])] =
+&[
+    ("src/canvas/normal.vert", include_bytes!("canvas/normal.vert")),
+    ("src/canvas/normal.frag", include_bytes!("canvas/normal.frag")),
+    ("src/canvas/indexed.frag", include_bytes!("canvas/indexed.frag")),
+    ("src/text/text.vert", include_bytes!("text/text.vert")),
+    ("src/text/text.frag", include_bytes!("text/text.frag")),
+    ("src/ui/selection/selection.vert", include_bytes!("ui/selection/selection.vert")),
+    ("src/ui/selection/selection.frag", include_bytes!("ui/selection/selection.frag")),
+    ("src/ui/background/background.vert", include_bytes!("ui/background/background.vert")),
+    ("src/ui/background/background.frag", include_bytes!("ui/background/background.frag")),
+    ("src/ui/grid/grid.vert", include_bytes!("ui/grid/grid.vert")),
+    ("src/ui/grid/grid.frag", include_bytes!("ui/grid/grid.frag")),
+    ("selecteur.png", include_bytes!("../selecteur.png")),
+    ("jackinput.ttf", include_bytes!("../jackinput.ttf")),
+];
+
+/// Path of an asset in the override directory, if one is set.
+pub fn override_path(name:&str) -> Option<PathBuf> {
+    env::var_os(OVERRIDE_DIR_VAR).map(|dir| PathBuf::from(dir).join(name))
+}
+
+/// Load an asset from the override directory if it exists there, or from the binary otherwise.
+pub fn load(name:&str) -> Result<Cow<'static, [u8]>, String> {
+    if let Some(path) = override_path(name).filter(|p| p.is_file()) {
+        return fs::read(&path)
+            .map(Cow::Owned)
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    EMBEDDED
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, data)| Cow::Borrowed(*data))
+        .ok_or_else(|| format!("{}: no such asset", name))
+}
+
+pub fn load_string(name:&str) -> Result<String, String> {
+    String::from_utf8(load(name)?.into_owned())
+        .map_err(|e| format!("{}: {}", name, e))
+}
+
+/// Directory where generated data, such as font atlases, is kept between runs. It is created if
+/// needed.
+pub fn cache_dir() -> Option<PathBuf> {
+    let dir = env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?
+        .join("pixy");
+
+    fs::create_dir_all(&dir).ok()?;
+    Some(dir)
+}
+
+/// Minimum time between two checks of the watched files.
+const POLL_INTERVAL : Duration = Duration::from_millis(500);
+
+/// Watches assets of the override directory for modifications, by polling their modification
+/// time. Embedded assets never change, so nothing is reported when no override directory is set.
+pub struct Watcher {
+    files: Vec<(String, Option<SystemTime>)>,
+    last_poll: Instant,
+}
+
+impl Watcher {
+    pub fn new(names:&[&str]) -> Self {
+        Self {
+            files: names.iter().map(|name| (name.to_string(), modified(name))).collect(),
+            last_poll: Instant::now(),
+        }
+    }
+
+    /// Names of the watched assets which were modified since the last call.
+    pub fn changed(&mut self) -> Vec<String> {
+        if self.last_poll.elapsed() < POLL_INTERVAL {
+            return Vec::new()
+        }
+        self.last_poll = Instant::now();
+
+        self.files
+            .iter_mut()
+            .filter_map(|(name, time)| {
+                let new_time = modified(name);
+                if new_time != *time {
+                    *time = new_time;
+                    Some(name.clone())
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+fn modified(name:&str) -> Option<SystemTime> {
+    override_path(name)?.metadata().ok()?.modified().ok()
+}
@@ -0,0 +1,13 @@
+in vec2 texcoord;
+
+// palette indices, and the palette as a 256x1 texture
+uniform sampler2D tex;
+uniform sampler2D palette;
+
+out vec4 diffuseColor;
+
+void main()
+{
+    int index = int(texture(tex, texcoord).r * 255.0 + 0.5);
+    diffuseColor = texelFetch(palette, ivec2(index, 0), 0);
+}
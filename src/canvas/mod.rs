@@ -2,32 +2,225 @@ mod shader;
 
 pub use shader::*;
 
+use crate::color::{self, Color, BlendMode, Metric};
+
+/// Maximum number of colors of an indexed canvas.
+pub const MAX_INDEXED_COLORS : usize = 256;
+
+/// Pixels of an indexed canvas, as indices in its palette.
+#[derive(Clone)]
+pub struct Indexed {
+    pub indices : Vec<u8>,
+    pub palette : Vec<Color>,
+}
+
+impl Indexed {
+    /// Index colors in a palette made of the colors of `order`, then the other colors of `data`
+    /// in order of appearance. Fails if there are too many colors.
+    pub fn from_colors(data:&[Color], order:&[Color]) -> Result<Self, String> {
+        let mut palette : Vec<Color> = Vec::new();
+        for c in order.iter().chain(data.iter()) {
+            if !palette.contains(c) {
+                palette.push(*c);
+            }
+        }
+
+        if palette.len() > MAX_INDEXED_COLORS {
+            return Err(format!("{} colors, an indexed image has at most {}", palette.len(), MAX_INDEXED_COLORS))
+        }
+
+        let indices = data
+            .iter()
+            .map(|c| palette.iter().position(|p| p == c).unwrap() as u8)
+            .collect();
+
+        Ok(Self { indices, palette })
+    }
+}
+
 /// This structure represent a VIPix canvas:
 /// - Its size in pixels (Width, Height).
 /// - Its data (a big array of Width x Height pixels).
+/// - For indexed canvases, the palette index of each pixel. `data` is kept in sync with it.
+/// - The rectangle of pixels modified since the last call to `take_dirty`, if any, and whether
+///   the palette was modified.
+/// - The number of pixels painted with another color than asked because the palette was full.
 pub struct Canvas {
     pub size : (usize, usize),
-    pub data : Vec<(u8, u8, u8, u8)>,
+    pub data : Vec<Color>,
+    indexed : Option<Indexed>,
+    dirty : Option<((usize, usize), (usize, usize))>,
+    palette_dirty : bool,
+    snapped : usize,
 }
 
 impl Canvas {
     pub fn new(x:usize, y:usize) -> Self {
         Self {
             size: (x, y),
-            data: vec![(0, 0, 0, 255); x * y],
+            data: vec![Color::BLACK; x * y],
+            indexed: None,
+            dirty: None,
+            palette_dirty: false,
+            snapped: 0,
         }
     }
 
-    pub fn set_pixel_color(&mut self, x:usize, y:usize, rgba:(u8, u8, u8, u8)) {
+    /// Replace the whole content of the canvas, possibly changing its size. The canvas is no
+    /// longer indexed.
+    pub fn set_data(&mut self, size:(usize, usize), data:Vec<Color>) {
+        assert_eq!(size.0 * size.1, data.len());
+
+        self.size = size;
+        self.data = data;
+        self.indexed = None;
+        self.mark_all_dirty();
+    }
+
+    /// Set the color of a pixel. On an indexed canvas, the color is added to the palette if it
+    /// isn't in it, or replaced by the closest palette color if the palette is full, which is
+    /// counted by `take_snapped`.
+    pub fn set_pixel_color(&mut self, x:usize, y:usize, rgba:Color) {
         let (w, h) = self.size;
         let id = y * w + x;
 
         assert!(id < w*h);
 
-        self.data[id] = rgba;
+        let rgba = match &mut self.indexed {
+            Some(indexed) => {
+                let index = match indexed.palette.iter().position(|c| *c == rgba) {
+                    Some(index) => index,
+                    None if indexed.palette.len() < MAX_INDEXED_COLORS => {
+                        indexed.palette.push(rgba);
+                        self.palette_dirty = true;
+                        indexed.palette.len() - 1
+                    },
+                    None => {
+                        let nearest = Metric::Oklab.nearest(&indexed.palette, rgba).unwrap();
+                        self.snapped += 1;
+                        indexed.palette.iter().position(|c| *c == nearest).unwrap()
+                    },
+                };
+                indexed.indices[id] = index as u8;
+                indexed.palette[index]
+            },
+            None => rgba,
+        };
+
+        if self.data[id] != rgba {
+            self.data[id] = rgba;
+            self.mark_dirty(x, y);
+        }
+    }
+
+    pub fn indexed(&self) -> Option<&Indexed> {
+        self.indexed.as_ref()
+    }
+
+    pub fn is_indexed(&self) -> bool {
+        self.indexed.is_some()
+    }
+
+    /// Store the canvas as palette indices. The palette starts with the colors of `order`, then
+    /// has the other colors of the canvas. Fails if there are too many colors.
+    pub fn to_indexed(&mut self, order:&[Color]) -> Result<(), String> {
+        self.indexed = Some(Indexed::from_colors(&self.data, order)?);
+        self.palette_dirty = true;
+        self.mark_all_dirty();
+        Ok(())
+    }
+
+    /// Indexed version of the canvas: its own indices if it is indexed, otherwise as given by
+    /// `Indexed::from_colors`.
+    pub fn indexed_or(&self, order:&[Color]) -> Result<Indexed, String> {
+        match &self.indexed {
+            Some(indexed) => Ok(indexed.clone()),
+            None => Indexed::from_colors(&self.data, order),
+        }
+    }
+
+    /// Store the canvas as RGBA pixels again. The colors of the pixels are unchanged.
+    pub fn to_rgba(&mut self) {
+        if self.indexed.take().is_some() {
+            self.mark_all_dirty();
+        }
+    }
+
+    /// Change a color of the palette of an indexed canvas, which recolors all the pixels using it.
+    pub fn set_palette_color(&mut self, index:usize, rgba:Color) {
+        let indexed = match &mut self.indexed {
+            Some(indexed) if index < indexed.palette.len() => indexed,
+            _ => return,
+        };
+
+        indexed.palette[index] = rgba;
+        for (pixel, i) in self.data.iter_mut().zip(indexed.indices.iter()) {
+            if *i as usize == index {
+                *pixel = rgba;
+            }
+        }
+
+        self.palette_dirty = true;
+        self.mark_all_dirty();
     }
 
-    pub fn get_pixel_color(&self, x:usize, y:usize) -> (u8, u8, u8, u8) {
+    /// Return whether the palette of an indexed canvas was modified since the last call.
+    pub fn take_palette_dirty(&mut self) -> bool {
+        std::mem::replace(&mut self.palette_dirty, false)
+    }
+
+    /// Return the number of pixels painted with the closest palette color instead of the asked
+    /// one since the last call, because the palette of the indexed canvas was full.
+    pub fn take_snapped(&mut self) -> usize {
+        std::mem::replace(&mut self.snapped, 0)
+    }
+
+    /// Same as `region`, with the palette indices of an indexed canvas.
+    pub fn index_region(&self, (x1, y1):(usize, usize), (x2, y2):(usize, usize)) -> Vec<u8> {
+        let w = self.size.0;
+        let indices = self.indexed.as_ref().map_or(&[][..], |i| &i.indices[..]);
+        (y1..=y2)
+            .flat_map(|y| indices[y * w + x1 ..= y * w + x2].iter().copied())
+            .collect()
+    }
+
+    /// Paint a color over a pixel, composing it with the current color of the pixel.
+    pub fn paint_pixel(&mut self, x:usize, y:usize, rgba:Color, mode:BlendMode) {
+        let dst = self.get_pixel_color(x, y);
+        self.set_pixel_color(x, y, mode.blend(rgba, dst));
+    }
+
+    /// Grow the dirty rectangle so that it contains the given pixel.
+    pub fn mark_dirty(&mut self, x:usize, y:usize) {
+        self.dirty = Some(match self.dirty {
+            Some(((x1, y1), (x2, y2))) => ((x1.min(x), y1.min(y)), (x2.max(x), y2.max(y))),
+            None => ((x, y), (x, y)),
+        });
+    }
+
+    pub fn mark_all_dirty(&mut self) {
+        let (w, h) = self.size;
+        if w > 0 && h > 0 {
+            self.dirty = Some(((0, 0), (w - 1, h - 1)));
+        }
+    }
+
+    /// Return the topleft and bottomright (inclusive) corners of the pixels modified since the
+    /// last call, and mark the canvas as clean.
+    pub fn take_dirty(&mut self) -> Option<((usize, usize), (usize, usize))> {
+        self.dirty.take()
+    }
+
+    /// Copy the pixels of a rectangle given by its topleft and bottomright (inclusive) corners,
+    /// row by row.
+    pub fn region(&self, (x1, y1):(usize, usize), (x2, y2):(usize, usize)) -> Vec<Color> {
+        let w = self.size.0;
+        (y1..=y2)
+            .flat_map(|y| self.data[y * w + x1 ..= y * w + x2].iter().copied())
+            .collect()
+    }
+
+    pub fn get_pixel_color(&self, x:usize, y:usize) -> Color {
         let (w, h) = self.size;
         let id = y * w + x;
 
@@ -41,11 +234,7 @@ impl Canvas {
     }
 
     pub fn data_raw(&self) -> &[u8] {
-        unsafe {
-            let slice : &[(u8, u8, u8, u8)] = self.data.as_ref();
-            let ptr = slice.as_ptr() as *const u8;
-            core::slice::from_raw_parts(ptr, slice.len() * 4)
-        }
+        color::as_bytes(&self.data)
     }
 
     pub fn width(&self) -> usize {
@@ -57,16 +246,71 @@ impl Canvas {
     }
 }
 
-impl AsRef<[(u8, u8, u8, u8)]> for Canvas {
-    fn as_ref(&self) -> &[(u8, u8, u8, u8)] {
+impl std::ops::Deref for Canvas {
+    type Target = [Color];
+
+    fn deref(&self) -> &Self::Target {
         &self.data
     }
 }
 
-impl std::ops::Deref for Canvas {
-    type Target = [(u8, u8, u8, u8)];
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    fn deref(&self) -> &Self::Target {
-        &self.data
+    #[test]
+    fn indexed_round_trip() {
+        let (red, blue) = (Color::rgb(255, 0, 0), Color::rgba(0, 0, 255, 128));
+        let mut canvas = Canvas::new(3, 2);
+        canvas.set_pixel_color(0, 0, red);
+        canvas.set_pixel_color(2, 1, blue);
+        let data = canvas.data.clone();
+
+        // the palette starts with the given order, then the colors in order of appearance
+        canvas.to_indexed(&[blue]).unwrap();
+        let indexed = canvas.indexed().unwrap();
+        assert_eq!(indexed.palette, vec![blue, red, Color::BLACK]);
+        assert_eq!(indexed.indices, vec![1, 2, 2, 2, 2, 0]);
+        assert!(canvas.take_palette_dirty());
+        assert_eq!(canvas.data, data);
+
+        // new colors are added to the palette
+        let green = Color::rgb(0, 255, 0);
+        canvas.set_pixel_color(1, 0, green);
+        assert_eq!(canvas.indexed().unwrap().palette.len(), 4);
+        assert_eq!(canvas.indexed().unwrap().indices[1], 3);
+
+        // recoloring a palette entry changes all its pixels
+        canvas.set_palette_color(2, Color::WHITE);
+        assert_eq!(canvas.region((0, 0), (2, 1)), vec![red, green, Color::WHITE, Color::WHITE, Color::WHITE, blue]);
+        assert_eq!(canvas.index_region((1, 0), (2, 0)), vec![3, 2]);
+
+        canvas.to_rgba();
+        assert!(!canvas.is_indexed());
+        assert_eq!(canvas.data, vec![red, green, Color::WHITE, Color::WHITE, Color::WHITE, blue]);
+        assert_eq!(canvas.take_snapped(), 0);
+    }
+
+    #[test]
+    fn full_palette() {
+        let mut canvas = Canvas::new(16, 16);
+        for i in 0..MAX_INDEXED_COLORS {
+            canvas.set_pixel_color(i % 16, i / 16, Color::rgb(i as u8, 0, 0));
+        }
+        canvas.to_indexed(&[]).unwrap();
+
+        // too many colors to index
+        let mut other = Canvas::new(16, 17);
+        other.data[..256].copy_from_slice(&canvas.data);
+        other.data[256] = Color::WHITE;
+        assert!(other.to_indexed(&[]).is_err());
+
+        // a new color is replaced by the closest one, and counted
+        canvas.set_pixel_color(0, 0, Color::rgb(250, 10, 0));
+        canvas.set_pixel_color(1, 0, Color::rgb(12, 0, 0));
+        assert_eq!(canvas.get_pixel_color(0, 0), Color::rgb(250, 0, 0));
+        assert_eq!(canvas.indexed().unwrap().palette.len(), MAX_INDEXED_COLORS);
+        assert_eq!(canvas.take_snapped(), 1);
+        assert_eq!(canvas.take_snapped(), 0);
     }
 }
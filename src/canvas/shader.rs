@@ -30,3 +30,14 @@ pub struct ShaderInterface {
     #[uniform]
     view: Uniform<M33>,
 }
+
+/// Interface of `indexed.frag`, which looks the colors of an indexed canvas up in its palette.
+#[derive(UniformInterface)]
+pub struct IndexedShaderInterface {
+    #[uniform]
+    tex: Uniform<& 'static BoundTexture<'static, Dim2, NormUnsigned>>,
+    #[uniform]
+    palette: Uniform<& 'static BoundTexture<'static, Dim2, NormUnsigned>>,
+    #[uniform]
+    view: Uniform<M33>,
+}
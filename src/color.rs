@@ -0,0 +1,609 @@
+use std::{fmt, str::FromStr};
+
+/// An 8 bits per channel RGBA color, laid out like the texels of the canvas texture.
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub struct Color {
+    pub r:u8,
+    pub g:u8,
+    pub b:u8,
+    pub a:u8,
+}
+
+impl Color {
+    pub const BLACK : Color = Color::rgb(0, 0, 0);
+    pub const WHITE : Color = Color::rgb(255, 255, 255);
+
+    pub const fn rgba(r:u8, g:u8, b:u8, a:u8) -> Self {
+        Self { r, g, b, a }
+    }
+
+    pub const fn rgb(r:u8, g:u8, b:u8) -> Self {
+        Self { r, g, b, a: 255 }
+    }
+
+    /// Color from a hue in degrees, and saturation, lightness and alpha between 0 and 1.
+    pub fn hsla(h:f32, s:f32, l:f32, a:f32) -> Self {
+        let h = h.rem_euclid(360.0) / 60.0;
+        let s = s.max(0.0).min(1.0);
+        let l = l.max(0.0).min(1.0);
+
+        let c = (1.0 - (2.0 * l - 1.0).abs()) * s;
+        let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+        let (r, g, b) = match h as u32 {
+            0 => (c, x, 0.0),
+            1 => (x, c, 0.0),
+            2 => (0.0, c, x),
+            3 => (0.0, x, c),
+            4 => (x, 0.0, c),
+            _ => (c, 0.0, x),
+        };
+        let m = l - c * 0.5;
+
+        Self::rgba(unit_to_u8(r + m), unit_to_u8(g + m), unit_to_u8(b + m), unit_to_u8(a))
+    }
+
+    /// Hue in degrees, saturation and lightness between 0 and 1.
+    pub fn to_hsl(self) -> (f32, f32, f32) {
+        let [r, g, b, _] = self.to_f32();
+        let (max, min) = (r.max(g).max(b), r.min(g).min(b));
+        let (c, l) = (max - min, (max + min) * 0.5);
+
+        if c == 0.0 {
+            return (0.0, 0.0, l)
+        }
+
+        let h = if max == r {
+            ((g - b) / c).rem_euclid(6.0)
+        } else if max == g {
+            (b - r) / c + 2.0
+        } else {
+            (r - g) / c + 4.0
+        };
+        let s = c / (1.0 - (2.0 * l - 1.0).abs());
+
+        (h * 60.0, s, l)
+    }
+
+    /// Oklab coordinates (L, a, b) of the color, ignoring alpha.
+    pub fn to_oklab(self) -> [f32; 3] {
+        let [r, g, b, _] = self.to_f32();
+        let (r, g, b) = (srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b));
+
+        let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+        let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+        let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+
+        [
+            0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+            1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+            0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s,
+        ]
+    }
+
+    /// CIE L*a*b* coordinates of the color under the D65 illuminant, ignoring alpha.
+    pub fn to_lab(self) -> [f32; 3] {
+        let [r, g, b, _] = self.to_f32();
+        let (r, g, b) = (srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b));
+
+        let x = (0.4124564 * r + 0.3575761 * g + 0.1804375 * b) / 0.95047;
+        let y = 0.2126729 * r + 0.7151522 * g + 0.0721750 * b;
+        let z = (0.0193339 * r + 0.1191920 * g + 0.9503041 * b) / 1.08883;
+
+        let f = |t:f32| if t > 216.0 / 24389.0 { t.cbrt() } else { (24389.0 / 27.0 * t + 16.0) / 116.0 };
+        let (fx, fy, fz) = (f(x), f(y), f(z));
+
+        [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+    }
+
+    /// Channels as floats between 0 and 1.
+    pub fn to_f32(self) -> [f32; 4] {
+        [self.r as f32 / 255.0, self.g as f32 / 255.0, self.b as f32 / 255.0, self.a as f32 / 255.0]
+    }
+}
+
+impl From<(u8, u8, u8, u8)> for Color {
+    fn from((r, g, b, a):(u8, u8, u8, u8)) -> Self {
+        Self::rgba(r, g, b, a)
+    }
+}
+
+impl From<Color> for (u8, u8, u8, u8) {
+    fn from(c:Color) -> Self {
+        (c.r, c.g, c.b, c.a)
+    }
+}
+
+fn srgb_to_linear(c:f32) -> f32 {
+    if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+}
+
+/// Distances between colors. All of them account for alpha, so that a transparent pixel is not
+/// matched with an opaque color.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Metric {
+    /// Euclidean distance between the RGBA channels, which keeps the errors diffused by dithering
+    /// in the same space.
+    Rgb,
+    /// Euclidean distance in Oklab.
+    Oklab,
+    /// CIEDE2000 color difference, from CIE L*a*b*.
+    Ciede2000,
+}
+
+impl FromStr for Metric {
+    type Err = String;
+
+    fn from_str(s:&str) -> Result<Self, String> {
+        match s {
+            "rgb" => Ok(Metric::Rgb),
+            "oklab" => Ok(Metric::Oklab),
+            "ciede2000" => Ok(Metric::Ciede2000),
+            _ => Err(format!("unknown color distance: {}", s)),
+        }
+    }
+}
+
+impl Metric {
+    pub fn distance(self, c1:Color, c2:Color) -> f32 {
+        let alpha = (c1.a as f32 - c2.a as f32).abs() / 255.0;
+
+        match self {
+            Metric::Rgb => {
+                let (p, q) = (c1.to_f32(), c2.to_f32());
+                let d2 : f32 = p.iter().zip(q.iter()).map(|(a, b)| (a - b) * (a - b)).sum();
+                d2.sqrt()
+            },
+            Metric::Oklab => {
+                let (p, q) = (c1.to_oklab(), c2.to_oklab());
+                let d2 : f32 = p.iter().zip(q.iter()).map(|(a, b)| (a - b) * (a - b)).sum();
+                (d2 + alpha * alpha).sqrt()
+            },
+            // L* goes from 0 to 100, so is alpha
+            Metric::Ciede2000 => ciede2000(c1.to_lab(), c2.to_lab()) + alpha * 100.0,
+        }
+    }
+
+    /// Palette color closest to a color.
+    pub fn nearest(self, palette:&[Color], c:Color) -> Option<Color> {
+        palette
+            .iter()
+            .map(|p| (self.distance(*p, c), *p))
+            .min_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap())
+            .map(|(_, p)| p)
+    }
+}
+
+/// CIEDE2000 difference between two L*a*b* colors, as described by Sharma et al.
+fn ciede2000([l1, a1, b1]:[f32; 3], [l2, a2, b2]:[f32; 3]) -> f32 {
+    use std::f32::consts::PI;
+    let deg = |r:f32| r * 180.0 / PI;
+    let rad = |d:f32| d * PI / 180.0;
+
+    let c_mean = ((a1 * a1 + b1 * b1).sqrt() + (a2 * a2 + b2 * b2).sqrt()) * 0.5;
+    let c7 = c_mean.powi(7);
+    let g = 0.5 * (1.0 - (c7 / (c7 + 25f32.powi(7))).sqrt());
+
+    let (a1, a2) = (a1 * (1.0 + g), a2 * (1.0 + g));
+    let (c1, c2) = ((a1 * a1 + b1 * b1).sqrt(), (a2 * a2 + b2 * b2).sqrt());
+    let hue = |b:f32, a:f32| if a == 0.0 && b == 0.0 { 0.0 } else { deg(b.atan2(a)).rem_euclid(360.0) };
+    let (h1, h2) = (hue(b1, a1), hue(b2, a2));
+
+    let dl = l2 - l1;
+    let dc = c2 - c1;
+    let dh = if c1 * c2 == 0.0 {
+        0.0
+    } else if (h2 - h1).abs() <= 180.0 {
+        h2 - h1
+    } else if h2 <= h1 {
+        h2 - h1 + 360.0
+    } else {
+        h2 - h1 - 360.0
+    };
+    let dh = 2.0 * (c1 * c2).sqrt() * (rad(dh) * 0.5).sin();
+
+    let l_mean = (l1 + l2) * 0.5;
+    let c_mean = (c1 + c2) * 0.5;
+    let h_mean = if c1 * c2 == 0.0 {
+        h1 + h2
+    } else if (h1 - h2).abs() <= 180.0 {
+        (h1 + h2) * 0.5
+    } else if h1 + h2 < 360.0 {
+        (h1 + h2 + 360.0) * 0.5
+    } else {
+        (h1 + h2 - 360.0) * 0.5
+    };
+
+    let t = 1.0 - 0.17 * rad(h_mean - 30.0).cos() + 0.24 * rad(2.0 * h_mean).cos()
+        + 0.32 * rad(3.0 * h_mean + 6.0).cos() - 0.20 * rad(4.0 * h_mean - 63.0).cos();
+    let d_theta = 30.0 * (-((h_mean - 275.0) / 25.0).powi(2)).exp();
+    let c7 = c_mean.powi(7);
+    let rc = 2.0 * (c7 / (c7 + 25f32.powi(7))).sqrt();
+    let l50 = (l_mean - 50.0).powi(2);
+    let sl = 1.0 + 0.015 * l50 / (20.0 + l50).sqrt();
+    let sc = 1.0 + 0.045 * c_mean;
+    let sh = 1.0 + 0.015 * c_mean * t;
+    let rt = -(2.0 * rad(d_theta)).sin() * rc;
+
+    let (l, c, h) = (dl / sl, dc / sc, dh / sh);
+    (l * l + c * c + h * h + rt * c * h).sqrt()
+}
+
+/// View colors as their RGBA bytes, as uploaded to RGBA8 textures or saved to images.
+pub fn as_bytes(colors:&[Color]) -> &[u8] {
+    // Color is repr(C) with four u8 fields, so it is four bytes without padding
+    unsafe { core::slice::from_raw_parts(colors.as_ptr() as *const u8, colors.len() * 4) }
+}
+
+/// How a painted color is composed with the color of the pixel it is painted on.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BlendMode {
+    Normal,
+    Multiply,
+    Screen,
+    Add,
+    Overlay,
+    /// Keep the color of the pixel, and take the alpha of the painted color.
+    ReplaceAlpha,
+}
+
+const BLEND_MODES : & 'static [(& 'static str, BlendMode)] = &[
+    ("normal", BlendMode::Normal),
+    ("multiply", BlendMode::Multiply),
+    ("screen", BlendMode::Screen),
+    ("add", BlendMode::Add),
+    ("overlay", BlendMode::Overlay),
+    ("replacealpha", BlendMode::ReplaceAlpha),
+];
+
+impl BlendMode {
+    /// Paint `src` over `dst`. The blend function mixes the colors where both are opaque, and
+    /// the result is composed over `dst` with the usual "source over" rule.
+    pub fn blend(self, src:Color, dst:Color) -> Color {
+        if self == BlendMode::ReplaceAlpha {
+            return Color { a: src.a, ..dst }
+        }
+
+        let [sr, sg, sb, sa] = src.to_f32();
+        let [dr, dg, db, da] = dst.to_f32();
+
+        let mix = |s:f32, d:f32| {
+            let b = match self {
+                BlendMode::Normal | BlendMode::ReplaceAlpha => s,
+                BlendMode::Multiply => s * d,
+                BlendMode::Screen => s + d - s * d,
+                BlendMode::Add => (s + d).min(1.0),
+                BlendMode::Overlay => if d <= 0.5 { 2.0 * s * d } else { 1.0 - 2.0 * (1.0 - s) * (1.0 - d) },
+            };
+            // where the destination is transparent, the source color is used as is
+            (1.0 - da) * s + da * b
+        };
+
+        let a = sa + da * (1.0 - sa);
+        if a == 0.0 {
+            return Color::rgba(0, 0, 0, 0)
+        }
+        let over = |s:f32, d:f32| unit_to_u8((sa * mix(s, d) + da * d * (1.0 - sa)) / a);
+
+        Color::rgba(over(sr, dr), over(sg, dg), over(sb, db), unit_to_u8(a))
+    }
+}
+
+impl Default for BlendMode {
+    fn default() -> Self { BlendMode::Normal }
+}
+
+impl FromStr for BlendMode {
+    type Err = String;
+
+    fn from_str(s:&str) -> Result<Self, String> {
+        BLEND_MODES
+            .iter()
+            .find(|(name, _)| *name == s)
+            .map(|(_, mode)| *mode)
+            .ok_or_else(|| format!("unknown blend mode: {}", s))
+    }
+}
+
+impl fmt::Display for BlendMode {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        let name = BLEND_MODES.iter().find(|(_, mode)| mode == self).unwrap().0;
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseColorError(pub String);
+
+impl fmt::Display for ParseColorError {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid color: {}", self.0)
+    }
+}
+
+impl std::error::Error for ParseColorError {}
+
+/// Parse a CSS-like color: `#rgb`, `#rgba`, `#rrggbb`, `#rrggbbaa`, `rgb(r, g, b)`, `rgba(r, g,
+/// b, a)`, `hsl(h, s%, l%)`, `hsla(h, s%, l%, a)` or a CSS color name. Functions also accept the
+/// space separated syntax, e.g. `rgb(255 128 0 / 50%)`. Values out of their range are rejected.
+impl FromStr for Color {
+    type Err = ParseColorError;
+
+    fn from_str(s:&str) -> Result<Self, ParseColorError> {
+        let s = s.trim();
+        let err = || ParseColorError(s.to_string());
+
+        if let Some(hex) = s.strip_prefix('#') {
+            return parse_hex(hex).ok_or_else(err)
+        }
+
+        if let Some(i) = s.find('(') {
+            let (name, args) = (s[..i].trim().to_lowercase(), s[i + 1..].strip_suffix(')').ok_or_else(err)?);
+            let args : Vec<&str> = args.split(|c:char| c == ',' || c == '/' || c.is_whitespace())
+                .filter(|a| !a.is_empty())
+                .collect();
+
+            let alpha = match args.get(3) {
+                Some(a) => parse_unit(a).ok_or_else(err)?,
+                None if args.len() == 3 => 1.0,
+                None => return Err(err()),
+            };
+            if args.len() > 4 {
+                return Err(err())
+            }
+
+            return match name.as_str() {
+                "rgb" | "rgba" => {
+                    let channel = |a:&str| match a.strip_suffix('%') {
+                        Some(_) => parse_unit(a).map(unit_to_u8),
+                        None => a.parse::<f32>().ok().filter(|v| (0.0..=255.0).contains(v)).map(|v| v.round() as u8),
+                    };
+                    let (r, g, b) = (channel(args[0]), channel(args[1]), channel(args[2]));
+                    Ok(Color::rgba(r.ok_or_else(err)?, g.ok_or_else(err)?, b.ok_or_else(err)?, unit_to_u8(alpha)))
+                },
+                "hsl" | "hsla" => {
+                    let h = args[0].trim_end_matches("deg").parse::<f32>().map_err(|_| err())?;
+                    let percent = |a:&str| a.strip_suffix('%').and_then(|_| parse_unit(a));
+                    let (s, l) = (percent(args[1]).ok_or_else(err)?, percent(args[2]).ok_or_else(err)?);
+                    Ok(Color::hsla(h, s, l, alpha))
+                },
+                _ => Err(err()),
+            }
+        }
+
+        let name = s.to_lowercase();
+        NAMED_COLORS
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, rgb)| {
+                let [_, r, g, b] = rgb.to_be_bytes();
+                Color::rgb(r, g, b)
+            })
+            .map(|c| if name == "transparent" { Color { a: 0, ..c } } else { c })
+            .ok_or_else(err)
+    }
+}
+
+/// Colors are written as `#rrggbb`, or `#rrggbbaa` when they are not opaque.
+impl fmt::Display for Color {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.r, self.g, self.b)?;
+        if self.a != 255 {
+            write!(f, "{:02x}", self.a)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse hexadecimal digits, without the leading `#`.
+pub fn parse_hex(hex:&str) -> Option<Color> {
+    let digit = |i:usize| hex.get(i..i + 1).and_then(|d| u8::from_str_radix(d, 16).ok()).map(|d| d * 17);
+    let byte = |i:usize| hex.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok());
+
+    match hex.len() {
+        3 => Some(Color::rgb(digit(0)?, digit(1)?, digit(2)?)),
+        4 => Some(Color::rgba(digit(0)?, digit(1)?, digit(2)?, digit(3)?)),
+        6 => Some(Color::rgb(byte(0)?, byte(2)?, byte(4)?)),
+        8 => Some(Color::rgba(byte(0)?, byte(2)?, byte(4)?, byte(6)?)),
+        _ => None,
+    }
+}
+
+/// Parse a number between 0 and 1, or a percentage.
+fn parse_unit(s:&str) -> Option<f32> {
+    let v = match s.strip_suffix('%') {
+        Some(p) => p.parse::<f32>().ok().map(|p| p / 100.0),
+        None => s.parse::<f32>().ok(),
+    };
+    v.filter(|v| (0.0..=1.0).contains(v))
+}
+
+fn unit_to_u8(v:f32) -> u8 {
+    (v.max(0.0).min(1.0) * 255.0).round() as u8
+}
+
+/// CSS named colors, as 0xrrggbb.
+const NAMED_COLORS : & 'static [(& 'static str, u32)] = &[
+    ("aliceblue", 0xf0f8ff), ("antiquewhite", 0xfaebd7), ("aqua", 0x00ffff),
+    ("aquamarine", 0x7fffd4), ("azure", 0xf0ffff), ("beige", 0xf5f5dc),
+    ("bisque", 0xffe4c4), ("black", 0x000000), ("blanchedalmond", 0xffebcd),
+    ("blue", 0x0000ff), ("blueviolet", 0x8a2be2), ("brown", 0xa52a2a),
+    ("burlywood", 0xdeb887), ("cadetblue", 0x5f9ea0), ("chartreuse", 0x7fff00),
+    ("chocolate", 0xd2691e), ("coral", 0xff7f50), ("cornflowerblue", 0x6495ed),
+    ("cornsilk", 0xfff8dc), ("crimson", 0xdc143c), ("cyan", 0x00ffff),
+    ("darkblue", 0x00008b), ("darkcyan", 0x008b8b), ("darkgoldenrod", 0xb8860b),
+    ("darkgray", 0xa9a9a9), ("darkgreen", 0x006400), ("darkgrey", 0xa9a9a9),
+    ("darkkhaki", 0xbdb76b), ("darkmagenta", 0x8b008b), ("darkolivegreen", 0x556b2f),
+    ("darkorange", 0xff8c00), ("darkorchid", 0x9932cc), ("darkred", 0x8b0000),
+    ("darksalmon", 0xe9967a), ("darkseagreen", 0x8fbc8f), ("darkslateblue", 0x483d8b),
+    ("darkslategray", 0x2f4f4f), ("darkslategrey", 0x2f4f4f), ("darkturquoise", 0x00ced1),
+    ("darkviolet", 0x9400d3), ("deeppink", 0xff1493), ("deepskyblue", 0x00bfff),
+    ("dimgray", 0x696969), ("dimgrey", 0x696969), ("dodgerblue", 0x1e90ff),
+    ("firebrick", 0xb22222), ("floralwhite", 0xfffaf0), ("forestgreen", 0x228b22),
+    ("fuchsia", 0xff00ff), ("gainsboro", 0xdcdcdc), ("ghostwhite", 0xf8f8ff),
+    ("gold", 0xffd700), ("goldenrod", 0xdaa520), ("gray", 0x808080),
+    ("green", 0x008000), ("greenyellow", 0xadff2f), ("grey", 0x808080),
+    ("honeydew", 0xf0fff0), ("hotpink", 0xff69b4), ("indianred", 0xcd5c5c),
+    ("indigo", 0x4b0082), ("ivory", 0xfffff0), ("khaki", 0xf0e68c),
+    ("lavender", 0xe6e6fa), ("lavenderblush", 0xfff0f5), ("lawngreen", 0x7cfc00),
+    ("lemonchiffon", 0xfffacd), ("lightblue", 0xadd8e6), ("lightcoral", 0xf08080),
+    ("lightcyan", 0xe0ffff), ("lightgoldenrodyellow", 0xfafad2), ("lightgray", 0xd3d3d3),
+    ("lightgreen", 0x90ee90), ("lightgrey", 0xd3d3d3), ("lightpink", 0xffb6c1),
+    ("lightsalmon", 0xffa07a), ("lightseagreen", 0x20b2aa), ("lightskyblue", 0x87cefa),
+    ("lightslategray", 0x778899), ("lightslategrey", 0x778899), ("lightsteelblue", 0xb0c4de),
+    ("lightyellow", 0xffffe0), ("lime", 0x00ff00), ("limegreen", 0x32cd32),
+    ("linen", 0xfaf0e6), ("magenta", 0xff00ff), ("maroon", 0x800000),
+    ("mediumaquamarine", 0x66cdaa), ("mediumblue", 0x0000cd), ("mediumorchid", 0xba55d3),
+    ("mediumpurple", 0x9370db), ("mediumseagreen", 0x3cb371), ("mediumslateblue", 0x7b68ee),
+    ("mediumspringgreen", 0x00fa9a), ("mediumturquoise", 0x48d1cc), ("mediumvioletred", 0xc71585),
+    ("midnightblue", 0x191970), ("mintcream", 0xf5fffa), ("mistyrose", 0xffe4e1),
+    ("moccasin", 0xffe4b5), ("navajowhite", 0xffdead), ("navy", 0x000080),
+    ("oldlace", 0xfdf5e6), ("olive", 0x808000), ("olivedrab", 0x6b8e23),
+    ("orange", 0xffa500), ("orangered", 0xff4500), ("orchid", 0xda70d6),
+    ("palegoldenrod", 0xeee8aa), ("palegreen", 0x98fb98), ("paleturquoise", 0xafeeee),
+    ("palevioletred", 0xdb7093), ("papayawhip", 0xffefd5), ("peachpuff", 0xffdab9),
+    ("peru", 0xcd853f), ("pink", 0xffc0cb), ("plum", 0xdda0dd),
+    ("powderblue", 0xb0e0e6), ("purple", 0x800080), ("rebeccapurple", 0x663399),
+    ("red", 0xff0000), ("rosybrown", 0xbc8f8f), ("royalblue", 0x4169e1),
+    ("saddlebrown", 0x8b4513), ("salmon", 0xfa8072), ("sandybrown", 0xf4a460),
+    ("seagreen", 0x2e8b57), ("seashell", 0xfff5ee), ("sienna", 0xa0522d),
+    ("silver", 0xc0c0c0), ("skyblue", 0x87ceeb), ("slateblue", 0x6a5acd),
+    ("slategray", 0x708090), ("slategrey", 0x708090), ("snow", 0xfffafa),
+    ("springgreen", 0x00ff7f), ("steelblue", 0x4682b4), ("tan", 0xd2b48c),
+    ("teal", 0x008080), ("thistle", 0xd8bfd8), ("tomato", 0xff6347),
+    ("transparent", 0x000000), ("turquoise", 0x40e0d0), ("violet", 0xee82ee),
+    ("wheat", 0xf5deb3), ("white", 0xffffff), ("whitesmoke", 0xf5f5f5),
+    ("yellow", 0xffff00), ("yellowgreen", 0x9acd32),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s:&str) -> Option<Color> {
+        s.parse().ok()
+    }
+
+    #[test]
+    fn hex_colors() {
+        assert_eq!(parse("#f80"), Some(Color::rgb(255, 136, 0)));
+        assert_eq!(parse("#f808"), Some(Color::rgba(255, 136, 0, 136)));
+        assert_eq!(parse("#ff8800"), Some(Color::rgb(255, 136, 0)));
+        assert_eq!(parse("#ff880080"), Some(Color::rgba(255, 136, 0, 128)));
+        assert_eq!(Color::rgba(255, 136, 0, 128).to_string(), "#ff880080");
+        assert_eq!(Color::rgb(255, 136, 0).to_string(), "#ff8800");
+
+        assert_eq!(parse("#ff88"), Some(Color::rgba(255, 255, 136, 136)));
+        assert_eq!(parse("#ff8"), Some(Color::rgb(255, 255, 136)));
+        assert_eq!(parse("#f8"), None);
+        assert_eq!(parse("#ff880"), None);
+        assert_eq!(parse("#ff8800800"), None);
+        assert_eq!(parse("#gg8800"), None);
+    }
+
+    #[test]
+    fn functions() {
+        let orange = Color::rgba(255, 128, 0, 128);
+        assert_eq!(parse("rgb(255 128 0 / 50%)"), Some(orange));
+        assert_eq!(parse("rgba(255, 128, 0, 0.5)"), Some(orange));
+        assert_eq!(parse("RGB(100%, 50%, 0%)"), Some(Color::rgb(255, 128, 0)));
+        assert_eq!(parse("rgb(255, 128, 0)"), Some(Color::rgb(255, 128, 0)));
+
+        assert_eq!(parse("hsl(30, 100%, 50%)"), Some(Color::rgb(255, 128, 0)));
+        assert_eq!(parse("hsla(120deg 100% 25% / 0.5)"), Some(Color::rgba(0, 128, 0, 128)));
+        assert_eq!(parse("hsl(0, 0%, 100%)"), Some(Color::WHITE));
+
+        assert_eq!(parse("rgb(256, 0, 0)"), None);
+        assert_eq!(parse("rgb(-1, 0, 0)"), None);
+        assert_eq!(parse("rgb(0, 0, 0, 2)"), None);
+        assert_eq!(parse("rgb(0, 0, 110%)"), None);
+        assert_eq!(parse("hsl(0, 120%, 50%)"), None);
+        assert_eq!(parse("hsl(0, 100, 50)"), None);
+        assert_eq!(parse("rgb(0, 0)"), None);
+        assert_eq!(parse("rgb(0, 0, 0, 0, 0)"), None);
+        assert_eq!(parse("rgb(0, 0, 0"), None);
+        assert_eq!(parse("cmyk(0, 0, 0)"), None);
+    }
+
+    #[test]
+    fn hsl_round_trip() {
+        for &(h, s, l) in &[(30.0, 1.0, 0.5), (200.0, 0.5, 0.25), (330.0, 0.8, 0.75)] {
+            let (h2, s2, l2) = Color::hsla(h, s, l, 1.0).to_hsl();
+            assert!((h - h2).abs() < 1.0 && (s - s2).abs() < 0.01 && (l - l2).abs() < 0.01,
+                    "{:?} != {:?}", (h, s, l), (h2, s2, l2));
+        }
+        assert_eq!(Color::rgb(128, 128, 128).to_hsl(), (0.0, 0.0, 128.0 / 255.0));
+    }
+
+    #[test]
+    fn named_colors() {
+        assert_eq!(parse("rebeccapurple"), Some(Color::rgb(0x66, 0x33, 0x99)));
+        assert_eq!(parse(" Orange "), Some(Color::rgb(255, 165, 0)));
+        assert_eq!(parse("transparent"), Some(Color::rgba(0, 0, 0, 0)));
+        assert_eq!(parse("orangish"), None);
+        assert_eq!(parse(""), None);
+    }
+
+    #[test]
+    fn color_spaces() {
+        let close = |p:[f32; 3], q:[f32; 3], e:f32| p.iter().zip(q.iter()).all(|(a, b)| (a - b).abs() < e);
+        assert!(close(Color::WHITE.to_oklab(), [1.0, 0.0, 0.0], 1e-3));
+        assert!(close(Color::rgb(255, 0, 0).to_oklab(), [0.628, 0.2249, 0.1258], 1e-3));
+        assert!(close(Color::WHITE.to_lab(), [100.0, 0.0, 0.0], 1e-2));
+        assert!(close(Color::rgb(255, 0, 0).to_lab(), [53.24, 80.09, 67.20], 0.05));
+    }
+
+    #[test]
+    fn ciede2000_reference() {
+        // pairs from the test data of Sharma, Wu and Dalal
+        let pairs = [
+            ([50.0, 2.6772, -79.7751], [50.0, 0.0, -82.7485], 2.0425),
+            ([50.0, -1.0, 2.0], [50.0, 0.0, 0.0], 2.3669),
+            ([50.0, 2.5, 0.0], [73.0, 25.0, -18.0], 27.1492),
+        ];
+        for &(p, q, d) in &pairs {
+            assert!((ciede2000(p, q) - d).abs() < 1e-3, "{:?} {:?}: {} != {}", p, q, ciede2000(p, q), d);
+        }
+    }
+
+    #[test]
+    fn nearest_color() {
+        let palette = [Color::BLACK, Color::WHITE, Color::rgb(255, 0, 0)];
+        for &metric in &[Metric::Rgb, Metric::Oklab, Metric::Ciede2000] {
+            assert_eq!(metric.nearest(&palette, Color::rgb(200, 30, 40)), Some(Color::rgb(255, 0, 0)));
+            assert_eq!(metric.nearest(&palette, Color::rgb(20, 20, 20)), Some(Color::BLACK));
+        }
+        assert_eq!(Metric::Rgb.nearest(&[], Color::BLACK), None);
+    }
+
+    #[test]
+    fn blend_modes() {
+        let blend = |mode:BlendMode, s:(u8, u8, u8, u8), d:(u8, u8, u8, u8)| {
+            <(u8, u8, u8, u8)>::from(mode.blend(s.into(), d.into()))
+        };
+
+        // source over, and the source as is over a transparent pixel
+        assert_eq!(blend(BlendMode::Normal, (255, 0, 0, 128), (0, 0, 255, 255)), (128, 0, 127, 255));
+        assert_eq!(blend(BlendMode::Normal, (255, 0, 0, 128), (0, 0, 0, 0)), (255, 0, 0, 128));
+        assert_eq!(blend(BlendMode::Normal, (0, 0, 0, 0), (0, 0, 0, 0)), (0, 0, 0, 0));
+
+        assert_eq!(blend(BlendMode::Multiply, (128, 255, 0, 255), (255, 128, 255, 255)), (128, 128, 0, 255));
+        assert_eq!(blend(BlendMode::Screen, (128, 0, 0, 255), (128, 0, 255, 255)), (192, 0, 255, 255));
+        assert_eq!(blend(BlendMode::Add, (200, 100, 0, 255), (100, 100, 50, 255)), (255, 200, 50, 255));
+        assert_eq!(blend(BlendMode::Overlay, (255, 0, 255, 255), (64, 192, 128, 255)), (128, 129, 255, 255));
+        assert_eq!(blend(BlendMode::ReplaceAlpha, (255, 0, 0, 10), (1, 2, 3, 200)), (1, 2, 3, 10));
+
+        // the blended color is composed like a normal one
+        assert_eq!(blend(BlendMode::Multiply, (0, 0, 0, 128), (255, 255, 255, 255)), (127, 127, 127, 255));
+        assert_eq!(blend(BlendMode::Multiply, (0, 0, 0, 255), (255, 255, 255, 0)), (0, 0, 0, 255));
+    }
+
+    #[test]
+    fn blend_mode_names() {
+        for &(name, mode) in BLEND_MODES {
+            assert_eq!(name.parse::<BlendMode>(), Ok(mode));
+            assert_eq!(mode.to_string(), name);
+        }
+        assert!("darken".parse::<BlendMode>().is_err());
+    }
+}
@@ -0,0 +1,379 @@
+use std::{borrow::Cow, fs::File, io::{BufWriter, Write}, path::Path};
+
+use image::{imageops, codecs::png::PngEncoder, ColorType, RgbaImage};
+
+use crate::{canvas::Indexed, color::Color};
+
+/// Smallest PNG bit depth which can index `n` colors.
+fn bit_depth(n:usize) -> (png::BitDepth, usize) {
+    match n {
+        0..=2 => (png::BitDepth::One, 1),
+        3..=4 => (png::BitDepth::Two, 2),
+        5..=16 => (png::BitDepth::Four, 4),
+        _ => (png::BitDepth::Eight, 8),
+    }
+}
+
+/// Pack indices of `bits` bits, most significant first, each row starting on a new byte.
+fn pack_rows(indices:&[u8], width:usize, bits:usize) -> Vec<u8> {
+    let per_byte = 8 / bits;
+    let mut ret = Vec::with_capacity(indices.len() / per_byte + width);
+
+    for row in indices.chunks(width) {
+        for pixels in row.chunks(per_byte) {
+            let byte = pixels
+                .iter()
+                .enumerate()
+                .fold(0u8, |b, (i, p)| b | p << (8 - bits * (i + 1)));
+            ret.push(byte);
+        }
+    }
+
+    ret
+}
+
+fn create(path:&Path) -> Result<BufWriter<File>, String> {
+    File::create(path)
+        .map(BufWriter::new)
+        .map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Write an indexed PNG, with a PLTE chunk in the order of the palette, a tRNS chunk if some
+/// colors are not opaque, and the smallest bit depth which fits the palette.
+pub fn write_indexed_png<P:AsRef<Path>>(path:P, (w, h):(usize, usize), image:&Indexed) -> Result<(), String> {
+    let path = path.as_ref();
+    let err = |e:png::EncodingError| format!("{}: {}", path.display(), e);
+    let (depth, bits) = bit_depth(image.palette.len());
+
+    let mut encoder = png::Encoder::new(create(path)?, w as u32, h as u32);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(depth);
+    encoder.set_palette(image.palette.iter().flat_map(|c| vec![c.r, c.g, c.b]).collect());
+
+    // tRNS can stop at the last translucent color, the following ones are opaque
+    if let Some(last) = image.palette.iter().rposition(|c| c.a != 255) {
+        encoder.set_trns(image.palette[..=last].iter().map(|c| c.a).collect());
+    }
+
+    let mut writer = encoder.write_header().map_err(err)?;
+    writer.write_image_data(&pack_rows(&image.indices, w, bits)).map_err(err)
+}
+
+/// Index of the transparent color of a GIF palette. GIF has no partial transparency, and only
+/// one color can be transparent.
+pub fn gif_transparent_index(palette:&[Color]) -> Result<Option<u8>, String> {
+    if palette.iter().any(|c| c.a != 0 && c.a != 255) {
+        return Err(String::from("GIF doesn't support translucent colors"))
+    }
+
+    let mut transparent = palette.iter().enumerate().filter(|(_, c)| c.a == 0);
+    let first = transparent.next().map(|(i, _)| i as u8);
+    if transparent.next().is_some() {
+        return Err(String::from("GIF supports a single transparent color"))
+    }
+
+    Ok(first)
+}
+
+/// GIF color tables have a power of two size, at least 2.
+pub fn gif_palette(palette:&[Color]) -> Vec<u8> {
+    let size = palette.len().max(2).next_power_of_two();
+    let mut ret : Vec<u8> = palette.iter().flat_map(|c| vec![c.r, c.g, c.b]).collect();
+    ret.resize(size * 3, 0);
+    ret
+}
+
+/// Write a single frame GIF, with the palette as global color table in the same order.
+pub fn write_gif<P:AsRef<Path>>(path:P, (w, h):(usize, usize), image:&Indexed) -> Result<(), String> {
+    let path = path.as_ref();
+    let err = |e:gif::EncodingError| format!("{}: {}", path.display(), e);
+
+    if w > u16::MAX as usize || h > u16::MAX as usize {
+        return Err(format!("{}: image too large for GIF", path.display()))
+    }
+
+    let transparent = gif_transparent_index(&image.palette)?;
+    let mut encoder = gif::Encoder::new(create(path)?, w as u16, h as u16, &gif_palette(&image.palette)).map_err(err)?;
+
+    let frame = gif::Frame {
+        width: w as u16,
+        height: h as u16,
+        transparent,
+        buffer: Cow::Borrowed(&image.indices),
+        ..gif::Frame::default()
+    };
+    encoder.write_frame(&frame).map_err(err)
+}
+
+/// A frame of an animation: its pixels, row by row, and how long it is shown in milliseconds.
+pub struct AnimFrame {
+    pub pixels:Vec<Color>,
+    pub delay:u32,
+}
+
+/// Write an animated GIF, or an APNG for the other extensions, upscaled `scale` times with nearest
+/// neighbor. `loops` is the number of times the animation is played, 0 meaning forever.
+pub fn write_animation<P:AsRef<Path>>(path:P, (w, h):(usize, usize), frames:&[AnimFrame], loops:u16,
+                                      scale:usize) -> Result<(), String>
+{
+    let path = path.as_ref();
+    if frames.is_empty() {
+        return Err(String::from("no frames to export"))
+    }
+
+    let (sw, sh) = ((w * scale) as u32, (h * scale) as u32);
+    let images : Vec<RgbaImage> = frames
+        .iter()
+        .map(|f| {
+            let bytes = f.pixels.iter().flat_map(|c| vec![c.r, c.g, c.b, c.a]).collect();
+            let image = RgbaImage::from_raw(w as u32, h as u32, bytes).expect("frame size mismatch");
+            imageops::resize(&image, sw, sh, imageops::FilterType::Nearest)
+        })
+        .collect();
+    let delays = frames.iter().map(|f| f.delay);
+
+    let is_gif = path.extension().map_or(false, |e| e.to_string_lossy().eq_ignore_ascii_case("gif"));
+    if is_gif {
+        write_animated_gif(path, images.into_iter().zip(delays), loops)
+    } else {
+        write_apng(path, images.into_iter().zip(delays), loops)
+    }
+}
+
+/// GIF frames are converted as the image crate's encoder does, but are disposed to the
+/// background, so that the previous frame doesn't show through the transparent pixels.
+fn write_animated_gif<I>(path:&Path, frames:I, loops:u16) -> Result<(), String>
+    where I : Iterator<Item = (RgbaImage, u32)>
+{
+    let err = |e:gif::EncodingError| format!("{}: {}", path.display(), e);
+    let mut frames = frames.peekable();
+    let (w, h) = match frames.peek() {
+        Some((image, _)) if image.width() <= u16::MAX as u32 && image.height() <= u16::MAX as u32 =>
+            (image.width() as u16, image.height() as u16),
+        _ => return Err(format!("{}: image too large for GIF", path.display())),
+    };
+
+    let mut encoder = gif::Encoder::new(create(path)?, w, h, &[]).map_err(err)?;
+    encoder.set_repeat(match loops {
+        0 => gif::Repeat::Infinite,
+        n => gif::Repeat::Finite(n - 1),
+    }).map_err(err)?;
+
+    for (image, delay) in frames {
+        // GIF only has 1-bit alpha: translucent pixels are made opaque or transparent, and all
+        // transparent pixels share a single color, so that only one palette index is transparent
+        let mut pixels = image.into_raw();
+        for pixel in pixels.chunks_exact_mut(4) {
+            if pixel[3] >= 128 {
+                pixel[3] = 255;
+            } else {
+                pixel.copy_from_slice(&[0, 0, 0, 0]);
+            }
+        }
+
+        let mut frame = gif::Frame::from_rgba_speed(w, h, &mut pixels, 10);
+        // GIF delays are in hundredths of a second
+        frame.delay = ((delay + 5) / 10).min(u16::MAX as u32) as u16;
+        frame.dispose = gif::DisposalMethod::Background;
+        encoder.write_frame(&frame).map_err(err)?;
+    }
+
+    Ok(())
+}
+
+/// Chunks of a PNG file, as (type, data).
+fn png_chunks(data:&[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut ret = Vec::new();
+    let mut rest = &data[8..];
+
+    while rest.len() >= 12 {
+        let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        let kind = [rest[4], rest[5], rest[6], rest[7]];
+        ret.push((kind, &rest[8..8 + len]));
+        rest = &rest[12 + len..];
+    }
+
+    ret
+}
+
+fn write_png_chunk<W:Write>(w:&mut W, kind:&[u8; 4], data:&[u8]) -> std::io::Result<()> {
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(data);
+
+    w.write_all(&(data.len() as u32).to_be_bytes())?;
+    w.write_all(kind)?;
+    w.write_all(data)?;
+    w.write_all(&crc.finalize().to_be_bytes())
+}
+
+/// The png crate can't write APNG: each frame is encoded as a PNG by the image crate, and its
+/// IDAT chunks are moved to the animation, as fdAT chunks after the first frame.
+fn write_apng<I>(path:&Path, frames:I, loops:u16) -> Result<(), String>
+    where I : ExactSizeIterator<Item = (RgbaImage, u32)>
+{
+    let io_err = |e:std::io::Error| format!("{}: {}", path.display(), e);
+    let mut file = create(path)?;
+    let num_frames = frames.len() as u32;
+    let mut sequence = 0u32;
+
+    for (i, (image, delay)) in frames.enumerate() {
+        let mut png = Vec::new();
+        PngEncoder::new(&mut png)
+            .encode(image.as_raw(), image.width(), image.height(), ColorType::Rgba8)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        let chunks = png_chunks(&png);
+
+        if i == 0 {
+            let ihdr = chunks.iter().find(|(k, _)| k == b"IHDR").expect("PNG without IHDR").1;
+            let mut actl = num_frames.to_be_bytes().to_vec();
+            actl.extend_from_slice(&(loops as u32).to_be_bytes());
+
+            file.write_all(&png[..8]).map_err(io_err)?;
+            write_png_chunk(&mut file, b"IHDR", ihdr).map_err(io_err)?;
+            write_png_chunk(&mut file, b"acTL", &actl).map_err(io_err)?;
+        }
+
+        // full size frames replace the previous one, including its alpha
+        let mut fctl = Vec::with_capacity(26);
+        fctl.extend_from_slice(&sequence.to_be_bytes());
+        fctl.extend_from_slice(&image.width().to_be_bytes());
+        fctl.extend_from_slice(&image.height().to_be_bytes());
+        fctl.extend_from_slice(&[0; 8]);
+        fctl.extend_from_slice(&(delay.min(u16::MAX as u32) as u16).to_be_bytes());
+        fctl.extend_from_slice(&1000u16.to_be_bytes());
+        fctl.extend_from_slice(&[0, 0]);
+        write_png_chunk(&mut file, b"fcTL", &fctl).map_err(io_err)?;
+        sequence += 1;
+
+        for (_, data) in chunks.iter().filter(|(k, _)| k == b"IDAT") {
+            if i == 0 {
+                write_png_chunk(&mut file, b"IDAT", data).map_err(io_err)?;
+            } else {
+                let mut fdat = sequence.to_be_bytes().to_vec();
+                fdat.extend_from_slice(data);
+                write_png_chunk(&mut file, b"fdAT", &fdat).map_err(io_err)?;
+                sequence += 1;
+            }
+        }
+    }
+
+    write_png_chunk(&mut file, b"IEND", &[]).map_err(io_err)?;
+    file.flush().map_err(io_err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name:&str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("pixy-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn bit_depths() {
+        assert_eq!(bit_depth(1).1, 1);
+        assert_eq!(bit_depth(3).1, 2);
+        assert_eq!(bit_depth(16).1, 4);
+        assert_eq!(bit_depth(17).1, 8);
+    }
+
+    #[test]
+    fn packed_rows() {
+        // 3 pixels of 2 bits, the last 2 bits of each row are padding
+        let packed = pack_rows(&[0, 1, 2, 3, 2, 1], 3, 2);
+        assert_eq!(packed, vec![0b00_01_10_00, 0b11_10_01_00]);
+
+        assert_eq!(pack_rows(&[1, 0, 1, 1, 1, 0, 0, 1, 1], 9, 1), vec![0b1011_1001, 0b1000_0000]);
+        assert_eq!(pack_rows(&[200, 7], 2, 8), vec![200, 7]);
+    }
+
+    #[test]
+    fn indexed_png() {
+        let (red, clear, half) = (Color::rgb(255, 0, 0), Color::rgba(0, 0, 0, 0), Color::rgba(0, 0, 255, 128));
+        let image = Indexed {
+            indices:vec![0, 1, 2, 2, 1, 0],
+            palette:vec![clear, half, red],
+        };
+
+        let path = temp_path("indexed.png");
+        write_indexed_png(&path, (3, 2), &image).unwrap();
+        let mut decoder = png::Decoder::new(File::open(&path).unwrap());
+        decoder.set_transformations(png::Transformations::IDENTITY);
+        let (output, mut reader) = decoder.read_info().unwrap();
+        let mut data = vec![0; output.buffer_size()];
+        reader.next_frame(&mut data).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let info = reader.info();
+        assert_eq!(info.color_type, png::ColorType::Indexed);
+        assert_eq!(info.bit_depth, png::BitDepth::Two);
+        assert_eq!(info.palette.as_deref(), Some(&[0, 0, 0, 0, 0, 255, 255, 0, 0][..]));
+        // the opaque color at the end of the palette is left out of tRNS
+        assert_eq!(info.trns.as_deref(), Some(&[0, 128][..]));
+        assert_eq!(data, vec![0b00_01_10_00, 0b10_01_00_00]);
+
+        // no tRNS at all for an opaque palette
+        let image = Indexed { indices:vec![0, 0, 0], palette:vec![red] };
+        write_indexed_png(&path, (3, 1), &image).unwrap();
+        let (_, reader) = png::Decoder::new(File::open(&path).unwrap()).read_info().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(reader.info().trns, None);
+    }
+
+    #[test]
+    fn gif_transparency() {
+        let (red, clear) = (Color::rgb(255, 0, 0), Color::rgba(0, 0, 0, 0));
+        assert_eq!(gif_transparent_index(&[red, clear]), Ok(Some(1)));
+        assert_eq!(gif_transparent_index(&[red]), Ok(None));
+        assert_eq!(gif_transparent_index(&[red, Color::rgba(0, 0, 0, 128)]),
+                   Err(String::from("GIF doesn't support translucent colors")));
+        assert_eq!(gif_transparent_index(&[clear, red, clear]),
+                   Err(String::from("GIF supports a single transparent color")));
+    }
+
+    #[test]
+    fn gif_palette_padding() {
+        let c = Color::rgb(1, 2, 3);
+        assert_eq!(gif_palette(&[c]), vec![1, 2, 3, 0, 0, 0]);
+        assert_eq!(gif_palette(&[c; 3]).len(), 4 * 3);
+        assert_eq!(gif_palette(&[c; 5]).len(), 8 * 3);
+        assert_eq!(&gif_palette(&[c; 5])[12..18], &[1, 2, 3, 0, 0, 0]);
+    }
+
+    #[test]
+    fn apng_frames() {
+        let (red, blue) = (Color::rgb(255, 0, 0), Color::rgb(0, 0, 255));
+        let frames = [
+            AnimFrame { pixels:vec![red; 4], delay:100 },
+            AnimFrame { pixels:vec![blue; 4], delay:50 },
+            AnimFrame { pixels:vec![red, blue, blue, red], delay:200 },
+        ];
+
+        let path = temp_path("anim.png");
+        write_animation(&path, (2, 2), &frames, 3, 2).unwrap();
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let chunks = png_chunks(&data);
+        let kinds : Vec<_> = chunks.iter().map(|(k, _)| k).collect();
+        assert_eq!(kinds.first(), Some(&b"IHDR"));
+        assert_eq!(kinds.last(), Some(&b"IEND"));
+
+        // acTL holds the number of frames and of plays
+        let actl = chunks.iter().find(|(k, _)| k == b"acTL").unwrap().1;
+        assert_eq!(actl, &[0, 0, 0, 3, 0, 0, 0, 3]);
+
+        // one fcTL per frame, with its upscaled size and delay in milliseconds
+        let fctls : Vec<_> = chunks.iter().filter(|(k, _)| k == b"fcTL").map(|(_, d)| *d).collect();
+        assert_eq!(fctls.len(), 3);
+        assert_eq!(&fctls[1][4..12], &[0, 0, 0, 4, 0, 0, 0, 4]);
+        assert_eq!(&fctls[1][20..24], &[0, 50, 3, 232]);
+        assert!(kinds.iter().any(|k| *k == b"fdAT"));
+
+        // decoders without APNG support show the first frame
+        let image = image::load_from_memory(&data).unwrap().to_rgba8();
+        assert_eq!(image.dimensions(), (4, 4));
+        assert_eq!(image.get_pixel(3, 3).0, [255, 0, 0, 255]);
+    }
+}
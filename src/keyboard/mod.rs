@@ -1,5 +1,5 @@
 use glfw::Key;
-use std::collections::HashMap;
+use std::{fmt, str::FromStr, collections::HashMap};
 
 pub mod azerty;
 
@@ -218,6 +218,52 @@ impl From<Mod> for ModSet {
     }
 }
 
+/// Modifier prefixes in key notation, in the order they are printed.
+const MOD_PREFIXES : & 'static [(& 'static str, Mod)] =
+&[
+    ("C-", Mod::Control),
+    ("S-", Mod::Shift),
+    ("A-", Mod::Alt),
+    ("AltGr-", Mod::AltGr),
+];
+
+/// Strip one modifier prefix from `s`, provided something remains to name the key.
+fn strip_mod_prefix(s:&str) -> Option<(Mod, &str)> {
+    MOD_PREFIXES
+        .iter()
+        .find_map(|&(p, m)| s.strip_prefix(p).filter(|rest| !rest.is_empty()).map(|rest| (m, rest)))
+}
+
+impl fmt::Display for ModSet {
+    /// Prints the modifiers as key notation prefixes, e.g. `C-S-`.
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        for &(p, m) in MOD_PREFIXES {
+            if self.is_set(m) {
+                f.write_str(p)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ModSet {
+    type Err = ParseKeyError;
+
+    fn from_str(s:&str) -> Result<ModSet, ParseKeyError> {
+        let mut set = ModSet::empty();
+        let mut rest = s;
+        while !rest.is_empty() {
+            let &(p, m) = MOD_PREFIXES
+                .iter()
+                .find(|(p, _)| rest.starts_with(p))
+                .ok_or_else(|| ParseKeyError(s.to_string()))?;
+            set.set(m);
+            rest = &rest[p.len()..];
+        }
+        Ok(set)
+    }
+}
+
 pub struct KeyboardLayout {
     pub(self) map:HashMap<(Key, ModSet), CharKey>,
 }
@@ -245,56 +291,131 @@ pub enum CharKey {
     Special(u32),
 }
 
+/// Names of the special keys, as written between `<` and `>`, with their code.
+const SPECIAL_NAMES : & 'static [(& 'static str, u32)] =
+&[
+    ("Esc", 0),
+    ("F1", 1),
+    ("F2", 2),
+    ("F3", 3),
+    ("F4", 4),
+    ("F5", 5),
+    ("F6", 6),
+    ("F7", 7),
+    ("F8", 8),
+    ("F9", 9),
+    ("F10", 10),
+    ("F11", 11),
+    ("F12", 12),
+    ("Insert", 13),
+    ("Del", 14),
+    ("BS", 15),
+    ("Beg", 16),
+    ("End", 17),
+    ("PUp", 18),
+    ("PDown", 19),
+    ("Left", 20),
+    ("Down", 21),
+    ("Up", 22),
+    ("Right", 23),
+    ("CR", 24),
+    ("Tab", 28),
+];
+
+/// Printable characters which would be ambiguous in key notation, and their bracketed name.
+const CHAR_NAMES : & 'static [(& 'static str, char)] =
+&[
+    ("Space", ' '),
+    ("Less", '<'),
+    ("More", '>'),
+    ("Minus", '-'),
+];
+
+/// Error returned when a string is not valid key notation.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct ParseKeyError(pub String);
+
+impl fmt::Display for ParseKeyError {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid key notation: {:?}", self.0)
+    }
+}
+
+impl std::error::Error for ParseKeyError {}
+
+impl CharKey {
+    /// Parse the name of a key as found between `<` and `>` (e.g. `Esc`, `Space` or `x`).
+    fn from_name(name:&str) -> Result<CharKey, ParseKeyError> {
+        let mut chars = name.chars();
+        if let (Some(c), None) = (chars.next(), chars.next()) {
+            return Ok(CharKey::Char(c))
+        }
+
+        if let Some(&(_, c)) = CHAR_NAMES.iter().find(|(n, _)| *n == name) {
+            return Ok(CharKey::Char(c))
+        }
+
+        if let Some(&(_, code)) = SPECIAL_NAMES.iter().find(|(n, _)| *n == name) {
+            return Ok(CharKey::Special(code))
+        }
+
+        name.strip_prefix("Special")
+            .and_then(|code| code.parse().ok())
+            .map(CharKey::Special)
+            .ok_or_else(|| ParseKeyError(name.to_string()))
+    }
+
+    /// The name of this key as it would be written between `<` and `>`.
+    fn name(&self) -> String {
+        match *self {
+            CharKey::Char(c) => {
+                CHAR_NAMES.iter()
+                    .find(|(_, nc)| *nc == c)
+                    .map(|(n, _)| n.to_string())
+                    .unwrap_or_else(|| c.to_string())
+            },
+            CharKey::Special(code) => {
+                SPECIAL_NAMES.iter()
+                    .find(|(_, nc)| *nc == code)
+                    .map(|(n, _)| n.to_string())
+                    .unwrap_or_else(|| format!("Special{}", code))
+            },
+        }
+    }
+}
+
+impl FromStr for CharKey {
+    type Err = ParseKeyError;
+
+    fn from_str(s:&str) -> Result<CharKey, ParseKeyError> {
+        let mut chars = s.chars();
+        match (chars.next(), chars.next()) {
+            (None, _) => Err(ParseKeyError(s.to_string())),
+            (Some(c), None) => Ok(CharKey::Char(c)),
+            _ => {
+                if s.starts_with('<') && s.ends_with('>') {
+                    CharKey::from_name(&s[1..s.len() - 1])
+                } else {
+                    Err(ParseKeyError(s.to_string()))
+                }
+            },
+        }
+    }
+}
+
 impl From<&str> for CharKey {
     fn from(s:&str) -> CharKey {
-        if s.len() == 1 {
-            CharKey::Char(s.chars().next().unwrap())
-        } else {
-            let code : u32 = match s {
-                "<Esc>" => 0,
-                "<F1>" => 1,
-                "<F2>" => 2,
-                "<F3>" => 3,
-                "<F4>" => 4,
-                "<F5>" => 5,
-                "<F6>" => 6,
-                "<F7>" => 7,
-                "<F8>" => 8,
-                "<F9>" => 9,
-                "<F10>" => 10,
-                "<F11>" => 11,
-                "<F12>" => 12,
-                "<Insert>" => 13,
-                "<Del>" => 14,
-                "<BS>" => 15,
-                "<Beg>" => 16,
-                "<End>" => 17,
-                "<PUp>" => 18,
-                "<PDown>" => 19,
-                "<Left>" => 20,
-                "<Down>" => 21,
-                "<Up>" => 22,
-                "<Right>" => 23,
-                "<CR>" => 24,
-                "<Space>" => 25,
-                "<Less>" => 26,
-                "<More>" => 27,
-                "<Tab>" => 28,
-                "<Minus>" => 29,
-                _ => 1000,
-            };
-
-            if code == 25 {
-                CharKey::Char(' ')
-            } else if code == 29 {
-                CharKey::Char('-')
-            } else if code == 26 {
-                CharKey::Char('<')
-            } else if code == 27 {
-                CharKey::Char('>')
-            } else {
-                CharKey::Special(code)
-            }
+        s.parse().unwrap_or(CharKey::Special(1000))
+    }
+}
+
+impl fmt::Display for CharKey {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CharKey::Char(c) if !CHAR_NAMES.iter().any(|&(_, nc)| nc == c && nc != '-') => {
+                write!(f, "{}", c)
+            },
+            _ => write!(f, "<{}>", self.name()),
         }
     }
 }
@@ -305,59 +426,122 @@ pub struct CharKeyMod {
     pub mods:ModSet,
 }
 
+impl FromStr for CharKeyMod {
+    type Err = ParseKeyError;
+
+    /// Parse vim-like key notation: a single character (uppercase letters imply `Shift`), or a
+    /// bracketed key name with modifier prefixes such as `<C-S-+>`, `<A-x>` or `<AltGr-e>`.
+    fn from_str(s:&str) -> Result<CharKeyMod, ParseKeyError> {
+        let mut chars = s.chars();
+        if let (Some(c), None) = (chars.next(), chars.next()) {
+            let mods = if c.is_ascii_uppercase() { ModSet::shift() } else { ModSet::empty() };
+            return Ok(CharKeyMod { key:CharKey::Char(c), mods })
+        }
+
+        if !(s.starts_with('<') && s.ends_with('>')) || s.len() < 3 {
+            return Err(ParseKeyError(s.to_string()))
+        }
+
+        let mut mods = ModSet::empty();
+        let mut name = &s[1..s.len() - 1];
+        while let Some((m, rest)) = strip_mod_prefix(name) {
+            mods.set(m);
+            name = rest;
+        }
+
+        let key = CharKey::from_name(name).map_err(|_| ParseKeyError(s.to_string()))?;
+        Ok(CharKeyMod { key, mods })
+    }
+}
+
 impl From<&str> for CharKeyMod {
     fn from(s:&str) -> CharKeyMod {
-        let mut set = ModSet::empty();
-        if s.len() == 1 {
-            if s.chars().next().unwrap().is_ascii_uppercase() {
-                set.set(Mod::Shift)
-            }
+        s.parse().unwrap_or(CharKeyMod { key:CharKey::Special(1000), mods:ModSet::empty() })
+    }
+}
 
-            CharKeyMod {
-                key:s.into(),
-                mods:set,
-            }
+impl fmt::Display for CharKeyMod {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        let plain = match self.key {
+            CharKey::Char(c) if CHAR_NAMES.iter().any(|&(_, nc)| nc == c && nc != '-') => false,
+            CharKey::Char(c) if c.is_ascii_uppercase() => self.mods == ModSet::shift(),
+            CharKey::Char(_) => self.mods == ModSet::empty(),
+            CharKey::Special(_) => false,
+        };
+
+        if plain {
+            write!(f, "{}", self.key)
         } else {
-            let mut chars = s.chars();
-            let mut string = String::new();
-
-            chars.next();
-
-            while let Some(mut c) = chars.next() {
-                if c == 'S' || c == 'C' || c == 'A' {
-                    let nc = chars.by_ref().next().unwrap();
-                    if nc == '-' {
-                        set.set(match c {
-                            'S' => Mod::Shift,
-                            'C' => Mod::Control,
-                            'A' => Mod::Alt,
-                            _ => unreachable!(),
-                        });
-                        continue
-                    } else {
-                        string.push(c);
-                        c = nc;
-                    }
-                }
+            write!(f, "<{}{}>", self.mods, self.key.name())
+        }
+    }
+}
 
-                if c == '>' { break }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Keys covering plain characters, the characters with a bracketed name, named special keys
+    /// and unnamed special codes.
+    fn keys() -> Vec<CharKey> {
+        let chars = ('a'..='z').chain('A'..='Z').chain('0'..='9')
+            .chain(" !\"#$%&'()*+,-./:;<=>?@[\\]^_`{|}~éß€".chars())
+            .map(CharKey::Char);
+        let specials = SPECIAL_NAMES.iter().map(|&(_, code)| code).chain(vec![25, 99, 1000])
+            .map(CharKey::Special);
+        chars.chain(specials).collect()
+    }
 
-                string.push(c)
-            }
+    fn mod_sets() -> impl Iterator<Item=ModSet> {
+        (0..16).map(ModSet)
+    }
 
-            if string.len() != 1 {
-                string = format!("<{}>", string);
-            } else {
-                if s.chars().next().unwrap().is_ascii_uppercase() {
-                    set.set(Mod::Shift)
-                }
-            }
+    #[test]
+    fn mod_set_round_trip() {
+        for mods in mod_sets() {
+            assert_eq!(mods.to_string().parse::<ModSet>(), Ok(mods), "{}", mods);
+        }
+    }
 
-            let sref : &str = string.as_ref();
-            CharKeyMod {
-                key:sref.into(),
-                mods:set,
+    #[test]
+    fn char_key_round_trip() {
+        for key in keys() {
+            assert_eq!(key.to_string().parse::<CharKey>(), Ok(key), "{}", key);
+        }
+    }
+
+    #[test]
+    fn char_key_mod_round_trip() {
+        for key in keys() {
+            for mods in mod_sets() {
+                let k = CharKeyMod { key, mods };
+                assert_eq!(k.to_string().parse::<CharKeyMod>(), Ok(k), "{}", k);
             }
         }
     }
+
+    #[test]
+    fn notation() {
+        let parse = |s:&str| s.parse::<CharKeyMod>().unwrap();
+        assert_eq!(parse("x"), CharKeyMod { key:CharKey::Char('x'), mods:ModSet::empty() });
+        assert_eq!(parse("X"), CharKeyMod { key:CharKey::Char('X'), mods:ModSet::shift() });
+        assert_eq!(parse("<C-S-+>").mods, ModSet(0b0011));
+        assert_eq!(parse("<AltGr-e>").mods, ModSet::altgr());
+        assert_eq!(parse("<A-Space>").key, CharKey::Char(' '));
+        assert_eq!(parse("<Esc>").key, CharKey::Special(0));
+        assert_eq!(parse("<C-Minus>").to_string(), "<C-Minus>");
+    }
+
+    #[test]
+    fn invalid_notation() {
+        for s in &["", "ab", "<>", "<C-", "<C->", "<Foo>", "<C-Foo>", "<X-a>", "<Special>", "Esc>"] {
+            assert!(s.parse::<CharKeyMod>().is_err(), "{:?} parsed", s);
+        }
+        for s in &["", "ab", "<Foo>"] {
+            assert!(s.parse::<CharKey>().is_err(), "{:?} parsed", s);
+        }
+        for s in &["C", "C-X-", "Ctrl-"] {
+            assert!(s.parse::<ModSet>().is_err(), "{:?} parsed", s);
+        }
+    }
 }
@@ -1,56 +1,74 @@
 use msdfgen_lib; // forces linking with msdfgen library
+mod aseprite;
+mod assets;
 mod bitmap2d;
 mod canvas;
+mod color;
+mod export;
 mod keyboard;
 mod maths;
+mod palette;
+mod quantize;
+mod sheet;
+mod software;
 mod text;
 mod ui;
+mod upscale;
 
 use std::collections::{HashSet, HashMap};
-use std::fs;
 
-use image::{open, DynamicImage};
+use image::{RgbImage, RgbaImage};
 
 use luminance::{
     context::GraphicsContext,
     pipeline::PipelineState,
-    shader::program::Program,
+    shader::{
+        program::{Program, ProgramError},
+        stage::{StageError, Type as StageType},
+    },
     render_state::{RenderState},
     tess::{Mode, TessBuilder},
     texture::{Sampler, Wrap, MinFilter, MagFilter, Texture, Dim2, GenMipmaps},
-    pixel::{NormRGB8UI, NormRGBA8UI},
+    pixel::{NormR8UI, NormRGBA8UI},
     blending::{Factor, Equation},
 };
 
-use luminance_glfw::{Surface, GlfwSurface, WindowDim, WindowOpt, WindowEvent};
+use luminance_glfw::{Surface, GlfwSurface, WindowDim, WindowOpt, WindowEvent, MouseButton};
 
-use crate::canvas::{Canvas, ShaderInterface as CanvasUni, Semantics as CanvasSem};
+use crate::canvas::{Canvas, Indexed, ShaderInterface as CanvasUni, IndexedShaderInterface as IndexedUni, Semantics as CanvasSem};
 use crate::keyboard::CharKeyMod;
+use crate::software::SoftwareRenderer;
 use crate::maths::*;
 use crate::ui::{
     Ui,
+    KeySequence,
+    PointerEvent,
     uistate::{
         UiState,
-        VisualType
+        VisualType,
+        zoom_level,
     },
     selection::{
         Semantics as SelSem,
         ShaderInterface as SelUni
     },
-    background::{Semantics as BgSem}
+    background::{Semantics as BgSem},
+    grid::{ShaderInterface as GridUni, GRID_MIN_ZOOM, uniform_color},
 };
+use crate::color::{Color, BlendMode};
 use crate::text::{HAlign, VAlign, Semantics as TextSem, ShaderInterface as TextUni};
 
 /// Create the main UI object.
 fn create_ui() -> Ui<UiState> {
-    let mut ui = Ui::new(|ui: &mut Ui<UiState>, UiState { selection, canvas, palette, ..}, c| {
+    let mut ui = Ui::new(|ui: &mut Ui<UiState>, UiState { selection, canvas, palette, last_color, blend, ..}, c| {
         if let Some(color) = palette.get(&c) {
+            *last_color = Some(c);
             if selection.is_empty() {
                 let (x, y) = ui.cursor();
-                canvas.set_pixel_color(x, y, *color);
+                canvas.paint_pixel(x, y, *color, *blend);
             } else {
                 for &(x, y) in selection.iter() {
-                    canvas.set_pixel_color(x, y, *color);
+                    canvas.paint_pixel(x, y, *color, *blend);
                 }
             }
         }
@@ -70,6 +88,38 @@ fn create_ui() -> Ui<UiState> {
 
     ui.set_window_event_listener(Some(event_listener));
 
+    // Mouse: left click moves the cursor and left drag selects, the wheel zooms around the
+    // pointer and middle drag pans the canvas.
+    ui.set_pointer_listener(Some(|ui: &mut Ui<UiState>, state: &mut UiState, e| {
+        match e {
+            PointerEvent::Press(MouseButton::Button1, pos) => {
+                if let Some((x, y)) = state.pixel_at(pos) {
+                    if ui.get_mode() == ui::Mode::Visual {
+                        ui.set_mode(ui::Mode::Normal);
+                    }
+                    ui.set_cursor(x, y);
+                }
+            },
+            PointerEvent::Drag(MouseButton::Button1, _, to) => {
+                if let Some((x, y)) = state.pixel_at(to) {
+                    if ui.get_mode() == ui::Mode::Normal {
+                        state.visual_type = VisualType::Square;
+                        ui.set_mode(ui::Mode::Visual);
+                    }
+                    ui.set_cursor(x, y);
+                }
+            },
+            PointerEvent::Drag(MouseButton::Button3, from, to) => {
+                state.pan(from, to);
+            },
+            PointerEvent::Scroll(pos, dy) if dy != 0.0 => {
+                let zoom = zoom_level(state.zoom, dy.signum() as i32);
+                state.zoom_around(pos, zoom);
+            },
+            _ => {},
+        }
+    }));
+
     // For each of the H, J, K and L keys, we associate a movement described by a pair of integers.
     // For each of these pairs, we add an object to the UI event handling system.
     "hjkl."
@@ -86,25 +136,37 @@ fn create_ui() -> Ui<UiState> {
         });
 
     // Set the selected pixel's color to white
-    ui.add_verb("s", true, |_, UiState { canvas,.. }, positions| {
+    ui.add_verb("s", true, |_, UiState { canvas, blend, .. }, positions| {
         let positions = positions.unwrap();
         for &(x, y) in positions {
-            canvas.set_pixel_color(x, y, (255, 255, 255, 255));
+            canvas.paint_pixel(x, y, Color::WHITE, *blend);
         }
     });
 
-    // Zoom in the canvas.
-    ui.add_verb("<S-+>", false, |_, UiState { zoom, .. }, _| {
-        *zoom += 0.01
+    // Zoom in the canvas to the next integer pixel ratio, around the cursor.
+    ui.add_verb("<S-+>", false, |ui, state, _| {
+        let zoom = zoom_level(state.zoom, 1);
+        state.zoom_on_pixel(ui.cursor(), zoom);
     });
 
     // Zoom out the canvas
-    ui.add_verb("-", false, |_, UiState { zoom, .. }, _| {
-        *zoom -= 0.01;
+    ui.add_verb("-", false, |ui, state, _| {
+        let zoom = zoom_level(state.zoom, -1);
+        state.zoom_on_pixel(ui.cursor(), zoom);
+    });
+
+    // Center the view on the cursor once moved by the object. As `z` is also an object which
+    // doesn't move, `zz` centers the view on the cursor.
+    ui.add_verb("z", true, |ui, state, _| {
+        state.center_on(ui.cursor());
+    });
+    ui.add_object("z", |ui, _, positions| {
+        positions.insert(ui.cursor());
     });
 
     // Enter command mode.
-    ui.add_verb(":", false, |ui, _, _| {
+    ui.add_verb(":", false, |ui, UiState { message, .. }, _| {
+        *message = None;
         ui.set_mode(ui::Mode::Command);
     });
 
@@ -169,23 +231,199 @@ fn create_ui() -> Ui<UiState> {
         ui.close()
     });
 
-    ui.add_command("color", |_, UiState { palette, .. }, args| {
-        let key = args[0].into();
-        let r = args[1].parse::<u8>().unwrap();
-        let g = args[2].parse::<u8>().unwrap();
-        let b = args[3].parse::<u8>().unwrap();
+    // Eyedropper: `gp{key}` assigns the color under the cursor to a key of the palette.
+    // On an indexed canvas, this recolors the pixels of the previous color of the key.
+    ui.add_key_verb("gp", |ui, state, key| {
+        let (x, y) = ui.cursor();
+        let color = state.canvas.get_pixel_color(x, y);
+        state.set_color(key, color);
+        state.message = Some(format!("{}:{}", key, color));
+    });
+
+    // `:color key color` assigns a color to a key of the palette, given in any syntax `Color`
+    // parses (`#f80`, `rgb(255, 136, 0)`, `orange`...) or as decimal `r g b [a]`. On an indexed
+    // canvas (`:set indexed`), this recolors the pixels of the previous color of the key.
+    ui.add_command("color", |_, state, args| {
+        let key = match args.get(0) {
+            Some(key) => key.parse::<CharKeyMod>(),
+            None => return state.message = Some(String::from("usage: color key color")),
+        };
 
-        let a = args.get(4).and_then(|p4| p4.parse::<u8>().ok()).unwrap_or(255);
+        let channels : Option<Vec<u8>> = args[1..].iter().map(|c| c.parse().ok()).collect();
+        let color = match channels.as_deref() {
+            Some([r, g, b]) => Ok(Color::rgb(*r, *g, *b)),
+            Some([r, g, b, a]) => Ok(Color::rgba(*r, *g, *b, *a)),
+            _ => args[1..].join(" ").parse::<Color>().map_err(|e| e.to_string()),
+        };
+
+        match (key, color) {
+            (Ok(key), Ok(color)) => state.set_color(key, color),
+            (Err(e), _) => state.message = Some(e.to_string()),
+            (_, Err(e)) => state.message = Some(e),
+        }
+    });
+
+    // `:palette load file` replaces the palette by the colors of a palette file, `:palette save
+    // file` writes it, and `:palette` alone lists the key of each color. `:palette extract
+    // [frequency|hue]` replaces it by the colors of the selection or canvas.
+    ui.add_command("palette", |_, state, args| {
+        let result = match args.get(0).cloned() {
+            None => Ok(state.palette_entries()
+                .iter()
+                .map(|(k, c)| format!("{}:{}", k, c))
+                .collect::<Vec<_>>()
+                .join(" ")),
+            Some("load") if args.len() == 2 => palette::load(args[1]).map(|colors| {
+                match state.set_palette(&colors) {
+                    0 => format!("loaded {} colors", colors.len()),
+                    n => format!("loaded {} colors, {} without a key", colors.len() - n, n),
+                }
+            }),
+            Some("extract") if args.len() <= 2 => {
+                let mut colors : Vec<Color> = state.used_colors().into_iter().map(|(c, _)| c).collect();
+                let sorted = match args.get(1).cloned() {
+                    None | Some("frequency") => Ok(()),
+                    Some("hue") => {
+                        // grays first, then by hue and lightness
+                        let key = |c:&Color| { let (h, s, l) = c.to_hsl(); (s > 0.0, h, l) };
+                        colors.sort_by(|a, b| key(a).partial_cmp(&key(b)).unwrap());
+                        Ok(())
+                    },
+                    Some(order) => Err(format!("unknown color order: {}", order)),
+                };
+                sorted.map(|_| match state.set_palette(&colors) {
+                    0 => format!("extracted {} colors", colors.len()),
+                    n => format!("extracted {} colors, {} without a key", colors.len() - n, n),
+                })
+            },
+            Some("save") if args.len() == 2 => {
+                let colors : Vec<_> = state.palette_entries().iter().map(|(_, c)| *c).collect();
+                palette::save(args[1], &colors).map(|_| format!("saved {} colors", colors.len()))
+            },
+            _ => Err(String::from("usage: palette [load|save file | extract [frequency|hue]]")),
+        };
 
-        palette.insert(key, (r, g, b, a));
+        state.message = Some(result.unwrap_or_else(|e| e));
     });
 
-    ui.add_command("zoom", |_, UiState { zoom, .. }, args| {
-        if let Ok(z) = args[0].parse::<f32>() {
-            *zoom = z;
+    // `:quantize N [none|ordered|fs]` reduces the selection, or the canvas, to N colors with
+    // median cut, optionally dithered, and makes them the palette.
+    ui.add_command("quantize", |_, state, args| {
+        let n = args.get(0).and_then(|n| n.parse::<usize>().ok()).filter(|n| *n > 0);
+        let dither = args.get(1).map_or(Ok(quantize::Dither::None), |d| d.parse());
+
+        state.message = Some(match (n, dither) {
+            (Some(n), Ok(dither)) => {
+                let colors = quantize::median_cut(&state.used_colors(), n);
+                let pixels = state.target_pixels();
+                let changed = quantize::apply(&mut state.canvas, &pixels, &colors, dither);
+                match state.set_palette(&colors) {
+                    0 => format!("quantized to {} colors, {} pixels changed", colors.len(), changed),
+                    k => format!("quantized to {} colors, {} pixels changed, {} colors without a key", colors.len(), changed, k),
+                }
+            },
+            (_, Err(e)) => e,
+            (None, _) => String::from("usage: quantize N [none|ordered|fs]"),
+        });
+    });
+
+    // `:remap [rgb|oklab|ciede2000] [old=new ...]` replaces the colors of the selection, or the
+    // canvas, by the closest palette color, except those given a replacement explicitly. Colors
+    // are palette keys or color literals.
+    ui.add_command("remap", |_, state, args| {
+        let mut metric = color::Metric::Oklab;
+        let mut table = HashMap::new();
+
+        for arg in args {
+            let entry = match arg.find('=') {
+                Some(i) => state.resolve_color(&arg[..i]).and_then(|old| {
+                    state.resolve_color(&arg[i + 1..]).map(|new| { table.insert(old, new); })
+                }),
+                None => arg.parse().map(|m| metric = m),
+            };
+
+            if let Err(e) = entry {
+                return state.message = Some(e)
+            }
+        }
+
+        let palette : Vec<Color> = state.palette_entries().iter().map(|(_, c)| *c).collect();
+        let pixels = state.target_pixels();
+        let changed = quantize::remap(&mut state.canvas, &pixels, &palette, &table, metric);
+        state.message = Some(format!("{} pixels changed", changed));
+    });
+
+    // `:s/old/new/[flags]` replaces the pixels of one color by another, in the selection or the
+    // canvas. Colors are palette keys or color literals, and any punctuation can be used as the
+    // delimiter. A number flag is a tolerance on each channel, and the `c` flag only selects the
+    // matches, so that they can be checked before substituting in the selection.
+    ui.add_raw_command("s", |_, state, args| {
+        let result = args.get(0)
+            .ok_or_else(|| String::from("usage: s/old/new/[flags]"))
+            .and_then(|arg| substitute(state, arg));
+
+        state.message = Some(result.unwrap_or_else(|e| e));
+    });
+
+    // Set options, e.g. `:set grid chunkgrid gridcolor=#202020ff blend=multiply`
+    ui.add_command("set", |_, state, args| {
+        for arg in args {
+            if let Err(e) = state.set_option(arg) {
+                state.message = Some(e);
+            }
+        }
+    });
+
+    // Render the next frame to an image file, without going through OpenGL.
+    ui.add_command("screenshot", |_, UiState { screenshot, .. }, args| {
+        if let Some(fname) = args.get(0) {
+            *screenshot = Some(fname.to_string());
+        }
+    });
+
+    // `:zoom fit` fits the canvas in the window, `:zoom 1:1` (or any `a:b` ratio, or a plain
+    // number) sets the number of screen pixels per canvas pixel.
+    ui.add_command("zoom", |ui, state, args| {
+        let zoom = match args.get(0).map(|a| a.split(':').collect::<Vec<_>>()).as_deref() {
+            Some(["fit"]) => return state.zoom_fit(),
+            Some([z]) => z.parse::<f32>().ok(),
+            Some([a, b]) => a.parse::<f32>().ok().zip(b.parse::<f32>().ok()).map(|(a, b)| a / b),
+            _ => None,
+        };
+
+        if let Some(zoom) = zoom.filter(|z| z.is_finite() && *z > 0.0) {
+            state.zoom_on_pixel(ui.cursor(), zoom);
         }
     });
 
+    // `:delay ms [all]` sets how long the frame under the cursor is shown, or all of them.
+    ui.add_command("delay", |ui, state, args| {
+        match (args.get(0).and_then(|d| d.parse::<u32>().ok()), args.get(1).cloned()) {
+            (Some(delay), Some("all")) => {
+                state.frame_delay = delay;
+                state.frame_delays.clear();
+            },
+            (Some(delay), None) => match state.frame_at(ui.cursor()) {
+                Some(frame) => { state.frame_delays.insert(frame, delay); },
+                None => state.message = Some(String::from("the cursor is not in a whole frame")),
+            },
+            _ => state.message = Some(String::from("usage: delay ms [all]")),
+        }
+    });
+
+    // `:export file.gif|file.png [loop=N] [scale=N]` writes the frames as an animated GIF or
+    // APNG, played N times (0, the default, loops forever). With any of `layout=grid|tight`,
+    // `padding=N`, `extrude=N` or `columns=N`, they are packed on a sprite sheet instead, along
+    // with a JSON description in the TexturePacker "hash" format.
+    ui.add_command("export", |_, state, args| {
+        let result = match args.get(0) {
+            Some(fname) => export_frames(state, fname, &args[1..]).map(|n| format!("exported {} frames to {}", n, fname)),
+            None => Err(String::from("usage: export file [loop=N] [scale=N] [layout=grid|tight] [padding=N] [extrude=N] [columns=N]")),
+        };
+
+        state.message = Some(result.unwrap_or_else(|e| e));
+    });
+
     // Empty action.
     ui.add_verb("_", true, |_,_,_| {});
 
@@ -204,45 +442,342 @@ fn create_ui() -> Ui<UiState> {
         selection.clear();
     });
 
-    ui.add_command("e", |_, UiState { ref mut canvas, ref mut filename, .. }, args| {
+    // `:e file` opens an image. Aseprite files have their frames laid out in a row, as chunks,
+    // and their palette assigned to the palette keys.
+    ui.add_command("e", |_, state, args| {
         if let Some(fname) = args.get(0) {
-            let image = image::open(fname).map(|img| img.into_rgba());
-
-            if let Ok(image) = image {
-                canvas.size = (image.width() as usize, image.height() as usize);
-                canvas.data = image.into_vec().chunks(4).map(|v| {
-                    if let [a,b,c,d] = v { (*a,*b,*c,*d) }
+            let ext = std::path::Path::new(fname).extension().map(|e| e.to_string_lossy().to_lowercase());
+
+            if let Some("ase") | Some("aseprite") = ext.as_deref() {
+                state.message = Some(load_aseprite(state, fname).unwrap_or_else(|e| e));
+            } else if let Ok(image) = image::open(fname).map(|img| img.into_rgba()) {
+                let size = (image.width() as usize, image.height() as usize);
+                state.canvas.set_data(size, image.into_vec().chunks(4).map(|v| {
+                    if let [a,b,c,d] = v { Color::rgba(*a,*b,*c,*d) }
                     else { unreachable!() }
-                }).collect();
+                }).collect());
             }
 
-            *filename = Some(fname.to_string())
+            state.filename = Some(fname.to_string())
         }
     });
 
-    ui.add_command("w", |_, UiState { ref canvas, ref mut filename, .. }, args| {
-        let fname = args.get(0).cloned().map(Into::into).or(filename.as_ref().cloned());
-        if let Some(fname) = fname {
-            let res = image::save_buffer(&fname, canvas.data_raw(), canvas.width() as u32, canvas.height() as u32, image::ColorType::Rgba8);
-            *filename = Some(fname);
-            println!("saving image resulted in: {:?}", res);
+    // `:w [file] [indexed] [scale=N] [filter=nearest|scale2x|scale3x|xbr]` saves the canvas,
+    // optionally upscaled. GIF files are always indexed, and PNG files are when the canvas is or
+    // when asked, with the palette in the order of the UI palette.
+    ui.add_command("w", |_, state, args| {
+        let mut indexed = false;
+        let mut fname = None;
+        let (mut scale, mut filter) = (1, Ok(upscale::Filter::Nearest));
+
+        for arg in args {
+            if *arg == "indexed" {
+                indexed = true;
+            } else if let Some(n) = arg.strip_prefix("scale=") {
+                match n.parse::<usize>().ok().filter(|n| *n > 0) {
+                    Some(n) => scale = n,
+                    None => return state.message = Some(format!("invalid scale: {}", n)),
+                }
+            } else if let Some(f) = arg.strip_prefix("filter=") {
+                filter = f.parse();
+            } else {
+                fname = Some(arg.to_string());
+            }
+        }
+
+        let filter = match filter {
+            Ok(filter) => filter,
+            Err(e) => return state.message = Some(e),
+        };
+
+        if let Some(fname) = fname.or(state.filename.clone()) {
+            let res = write_canvas(state, &fname, indexed, scale, filter);
+            state.message = Some(match res {
+                Ok(()) => format!("written {}", fname),
+                Err(e) => e,
+            });
+            // scaled copies don't replace the file being edited
+            if scale == 1 {
+                state.filename = Some(fname);
+            }
         }
     });
 
     ui
 }
 
-/// Retrieve the code from the vertex and fragment shader files and compile the corresponding
-/// shader program.
-fn compile_shader_program<S, I>(vert: &str, frag: &str) -> Program<S, (), I>
+/// Save the canvas to an image file, upscaled `scale` times with `filter`, as an indexed image if
+/// `indexed` is set, if the canvas is indexed or if the format requires it. The canvas itself is
+/// not modified.
+fn write_canvas(state:&UiState, fname:&str, indexed:bool, scale:usize, filter:upscale::Filter) -> Result<(), String> {
+    let canvas = &state.canvas;
+    let ext = std::path::Path::new(fname).extension().map(|e| e.to_string_lossy().to_lowercase());
+    let is_gif = ext.as_deref() == Some("gif");
+    let paletted = is_gif || ((indexed || canvas.is_indexed()) && ext.as_deref() == Some("png"));
+    if indexed && !paletted {
+        return Err(format!("{}: indexed images can only be written as PNG or GIF", fname))
+    }
+
+    let order = || -> Vec<Color> {
+        match canvas.indexed() {
+            Some(i) => i.palette.clone(),
+            None => state.palette_entries().iter().map(|(_, c)| *c).collect(),
+        }
+    };
+    let write_indexed = |size, image:&Indexed| {
+        if is_gif {
+            export::write_gif(fname, size, image)
+        } else {
+            export::write_indexed_png(fname, size, image)
+        }
+    };
+    let write_rgba = |(w, h):(usize, usize), bytes:&[u8]| {
+        image::save_buffer(fname, bytes, w as u32, h as u32, image::ColorType::Rgba8)
+            .map_err(|e| format!("{}: {}", fname, e))
+    };
+
+    // the canvas is written as is, without copying its pixels
+    if scale == 1 {
+        return if paletted {
+            write_indexed(canvas.size(), &canvas.indexed_or(&order())?)
+        } else {
+            write_rgba(canvas.size(), canvas.data_raw())
+        }
+    }
+
+    let (data, size) = upscale::scale(canvas, canvas.size(), scale, filter)?;
+    if paletted {
+        // the upscalers only use existing colors, so the scaled image has the same palette
+        write_indexed(size, &Indexed::from_colors(&data, &order())?)
+    } else {
+        write_rgba(size, color::as_bytes(&data))
+    }
+}
+
+/// Replace the canvas by the frames of an Aseprite file, and its palette, and describe the result
+/// with the unsupported features of the file.
+fn load_aseprite(state:&mut UiState, fname:&str) -> Result<String, String> {
+    let sprite = aseprite::load(fname)?;
+    let (w, h) = sprite.size;
+    let n = sprite.frames.len();
+    if n == 0 {
+        return Err(format!("{}: no frames", fname))
+    }
+
+    let mut data = Vec::with_capacity(w * h * n);
+    for y in 0..h {
+        for (pixels, _) in sprite.frames.iter() {
+            data.extend_from_slice(&pixels[y * w..(y + 1) * w]);
+        }
+    }
+
+    state.canvas.set_data((w * n, h), data);
+    state.chunk_size = (w, h);
+    state.frame_delays = sprite.frames.iter().map(|(_, delay)| *delay).enumerate().collect();
+    state.frame_tags = sprite.tags;
+    state.selection.clear();
+
+    let mut warnings = sprite.warnings;
+    if !sprite.palette.is_empty() {
+        let unassigned = state.set_palette(&sprite.palette);
+        if unassigned > 0 {
+            warnings.push(format!("{} colors without a key", unassigned));
+        }
+    }
+    if sprite.indexed {
+        if let Err(e) = state.set_indexed(true) {
+            warnings.push(e);
+        }
+    }
+
+    let loaded = format!("loaded {} frames of {}x{}", n, w, h);
+    Ok(if warnings.is_empty() { loaded } else { format!("{} ({})", loaded, warnings.join(", ")) })
+}
+
+/// Write the frames to an animation file, or to a sprite sheet if a sheet option is given (see
+/// the `export` command), and return their number.
+fn export_frames(state:&UiState, fname:&str, options:&[&str]) -> Result<usize, String> {
+    let (mut loops, mut scale) = (0, 1);
+    let mut sheet = None;
+
+    for opt in options {
+        let i = opt.find('=').ok_or_else(|| format!("unknown option: {}", opt))?;
+        let (name, value) = (&opt[..i], &opt[i + 1..]);
+        match name {
+            "loop" => loops = value.parse().map_err(|_| format!("invalid loop count: {}", value))?,
+            "scale" => scale = value.parse().ok().filter(|s| *s > 0).ok_or_else(|| format!("invalid scale: {}", value))?,
+            _ if sheet::Options::is_option(opt) => sheet.get_or_insert_with(sheet::Options::default).set(name, value)?,
+            _ => return Err(format!("unknown option: {}", name)),
+        }
+    }
+
+    let frames : Vec<export::AnimFrame> = (0..state.frame_count())
+        .map(|i| export::AnimFrame { pixels:state.frame_pixels(i), delay:state.frame_delay(i) })
+        .collect();
+
+    match sheet {
+        Some(options) => sheet::write(fname, state.frame_size(), &frames, &state.frame_tags, &options)?,
+        None => export::write_animation(fname, state.frame_size(), &frames, loops, scale)?,
+    }
+    Ok(frames.len())
+}
+
+/// Apply a `/old/new/[flags]` substitution (see the `s` command), and describe the result.
+fn substitute(state:&mut UiState, arg:&str) -> Result<String, String> {
+    let delim = arg.chars().next().ok_or_else(|| String::from("missing delimiter"))?;
+    let parts : Vec<&str> = arg[delim.len_utf8()..].splitn(3, delim).collect();
+    let (old, new, flags) = match parts[..] {
+        [old, new] => (old, new, ""),
+        [old, new, flags] => (old, new, flags),
+        _ => return Err(format!("expected {}old{}new{}", delim, delim, delim)),
+    };
+
+    let (old, new) = (state.resolve_color(old)?, state.resolve_color(new)?);
+    let confirm = flags.contains('c');
+    let digits : String = flags.chars().filter(|c| c.is_ascii_digit()).collect();
+    let tolerance = if digits.is_empty() { 0 } else { digits.parse::<u8>().map_err(|_| format!("invalid tolerance: {}", digits))? };
+
+    let matches : HashSet<(usize, usize)> = state.target_pixels()
+        .into_iter()
+        .filter(|&(x, y)| {
+            let c = state.canvas.get_pixel_color(x, y);
+            let close = |a:u8, b:u8| (a as i16 - b as i16).abs() <= tolerance as i16;
+            close(c.r, old.r) && close(c.g, old.g) && close(c.b, old.b) && close(c.a, old.a)
+        })
+        .collect();
+
+    if confirm {
+        let n = matches.len();
+        state.selection = matches;
+        return Ok(format!("{} matches selected", n))
+    }
+
+    for &(x, y) in matches.iter() {
+        state.canvas.set_pixel_color(x, y, new);
+    }
+    Ok(format!("{} pixels replaced", matches.len()))
+}
+
+/// Number of lines luminance prepends to the shader sources (`#version` and extensions), which
+/// must be removed from the line numbers reported by the driver.
+const GLSL_HEADER_LINES : usize = 2;
+
+/// Retrieve the code from the vertex and fragment shader assets and compile the corresponding
+/// shader program. Errors are reported as `file:line: message`.
+fn compile_shader_program<S, I>(vert: &str, frag: &str) -> Result<Program<S, (), I>, String>
     where S : luminance::vertex::Semantics,
           I : luminance::shader::program::UniformInterface,
 {
-    let vert_shader = fs::read_to_string(vert).unwrap();
-    let frag_shader = fs::read_to_string(frag).unwrap();
+    let vert_shader = assets::load_string(vert)?;
+    let frag_shader = assets::load_string(frag)?;
     Program::from_strings(None, &vert_shader, None, &frag_shader)
-        .expect("Couldn't compile OpenGL program")
-        .ignore_warnings()
+        .map(|program| program.ignore_warnings())
+        .map_err(|e| match e {
+            ProgramError::StageError(StageError::CompilationFailed(StageType::VertexShader, log)) =>
+                format_shader_log(vert, &log),
+            ProgramError::StageError(StageError::CompilationFailed(_, log)) =>
+                format_shader_log(frag, &log),
+            ProgramError::LinkFailed(log) =>
+                format!("linking {} and {} failed:\n{}", vert, frag, log.trim_end()),
+            e => format!("{}, {}: {}", vert, frag, e),
+        })
+}
+
+/// Rewrite a GLSL compilation log so that each message points to the line of `file` it is about.
+fn format_shader_log(file:&str, log:&str) -> String {
+    log
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| match parse_shader_log_line(l) {
+            Some((line, msg)) => format!("{}:{}: {}", file, line.saturating_sub(GLSL_HEADER_LINES), msg),
+            None => format!("{}: {}", file, l.trim()),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Extract the line number and the message from a line of a GLSL compilation log. Drivers use
+/// different formats: `0:12(5): error: ...` (Mesa), `0(12) : error ...` (Nvidia) and
+/// `ERROR: 0:12: ...` (AMD, Intel).
+fn parse_shader_log_line(l:&str) -> Option<(usize, String)> {
+    let (kind, rest) = match l.find(": ") {
+        Some(i) if l[..i].chars().all(|c| c.is_ascii_uppercase()) => (Some(&l[..i]), &l[i + 2..]),
+        _ => (None, l),
+    };
+
+    let rest = rest.trim_start_matches(|c:char| c.is_ascii_digit());
+    let rest = rest.strip_prefix(':').or_else(|| rest.strip_prefix('('))?;
+    let digits = rest.find(|c:char| !c.is_ascii_digit())?;
+    let line = rest[..digits].parse().ok()?;
+    let msg = &rest[digits..];
+    let msg = msg.find(": ").map(|i| &msg[i + 2..]).unwrap_or(msg).trim();
+
+    match kind {
+        Some(kind) => Some((line, format!("{}: {}", kind.to_lowercase(), msg))),
+        None => Some((line, msg.to_string())),
+    }
+}
+
+/// Recompile `program` if one of its sources is among the `changed` assets. If the compilation
+/// fails, the previous program is kept and the error is shown in the message line.
+fn reload_program<S, I>(program:&mut Program<S, (), I>, vert:&str, frag:&str, changed:&[String], message:&mut Option<String>)
+    where S : luminance::vertex::Semantics,
+          I : luminance::shader::program::UniformInterface,
+{
+    if changed.iter().any(|c| c == vert || c == frag) {
+        match compile_shader_program(vert, frag) {
+            Ok(p) => {
+                *program = p;
+                *message = Some(format!("reloaded {} and {}", vert, frag));
+            },
+            Err(e) => *message = Some(e),
+        }
+    }
+}
+
+/// Texture atlas of the selection borders.
+const SELECTION_ATLAS : &str = "selecteur.png";
+
+fn load_image_asset(name:&str) -> Result<RgbaImage, String> {
+    let data = assets::load(name)?;
+    image::load_from_memory(&data)
+        .map(|img| img.into_rgba())
+        .map_err(|e| format!("{}: {}", name, e))
+}
+
+/// Unwrap a result, or report the error and exit.
+fn or_exit<T>(res:Result<T, String>) -> T {
+    res.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1)
+    })
+}
+
+/// Render the current frame with the software renderer, from the same vertex buffers as the
+/// OpenGL passes.
+fn take_screenshot(state:&UiState, text_verts:&[text::Vertex], select_verts:&[ui::selection::Vertex],
+                   palette_verts:&[ui::background::Vertex], font_atlas:&RgbImage,
+                   sel_atlas:&RgbaImage) -> RgbaImage
+{
+    let (w, h) = state.window_size;
+    let mut renderer = SoftwareRenderer::new(w as u32, h as u32, [0.3, 0.3, 0.3, 1.0]);
+    let canvas_verts = state.render_canvas();
+    let canvas_view = state.canvas_view();
+
+    renderer.render_background(&ui::background::render_background(state.window_size));
+    renderer.render_canvas(&canvas_verts, canvas_view, &state.canvas);
+
+    let show_grid = state.grid && state.zoom >= GRID_MIN_ZOOM;
+    if show_grid || state.chunk_grid {
+        renderer.render_grid(&canvas_verts, canvas_view, state.canvas.size(), state.chunk_size,
+                             uniform_color(state.grid_color, show_grid),
+                             uniform_color(state.chunk_grid_color, state.chunk_grid));
+    }
+
+    renderer.render_selection(select_verts, canvas_view, sel_atlas);
+    renderer.render_background(palette_verts);
+    renderer.render_text(text_verts, state.text_view(), font_atlas);
+    renderer.into_image()
 }
 
 fn main() {
@@ -258,22 +793,30 @@ fn main() {
         .set_clear_color([0.3, 0.3, 0.3, 1.0])
         .enable_clear_color(true);
 
-    let program = compile_shader_program::<CanvasSem, CanvasUni>(
+    let mut program = or_exit(compile_shader_program::<CanvasSem, CanvasUni>(
         "src/canvas/normal.vert",
         "src/canvas/normal.frag"
-    );
-    let text_program = compile_shader_program::<TextSem, TextUni>(
+    ));
+    let mut indexed_program = or_exit(compile_shader_program::<CanvasSem, IndexedUni>(
+        "src/canvas/normal.vert",
+        "src/canvas/indexed.frag"
+    ));
+    let mut text_program = or_exit(compile_shader_program::<TextSem, TextUni>(
         "src/text/text.vert",
         "src/text/text.frag"
-    );
-    let select_program = compile_shader_program::<SelSem, SelUni>(
+    ));
+    let mut select_program = or_exit(compile_shader_program::<SelSem, SelUni>(
         "src/ui/selection/selection.vert",
         "src/ui/selection/selection.frag"
-    );
-    let bg_program = compile_shader_program::<BgSem, ()>(
+    ));
+    let mut bg_program = or_exit(compile_shader_program::<BgSem, ()>(
         "src/ui/background/background.vert",
         "src/ui/background/background.frag"
-    );
+    ));
+    let mut grid_program = or_exit(compile_shader_program::<CanvasSem, GridUni>(
+        "src/ui/grid/grid.vert",
+        "src/ui/grid/grid.frag"
+    ));
 
     let mut framebuffer = glfw.back_buffer().unwrap();
 
@@ -281,7 +824,7 @@ fn main() {
     println!("Loading font {}", fontname);
 
     let mut textb = text::TextRendererBuilder::for_resolution(16);
-    let fid = textb.add_font_from_ttf(fontname).unwrap();
+    let fid = textb.add_font_from_ttf_data(or_exit(assets::load(fontname)).into_owned());
 
     let text_sampler = Sampler {
         wrap_r: Wrap::ClampToEdge,
@@ -291,7 +834,7 @@ fn main() {
         mag_filter: MagFilter::Linear,
         depth_comparison: None,
     };
-    let text = textb.build(&mut glfw, text_sampler)
+    let mut text = textb.build(&mut glfw, text_sampler)
         .expect("Cannot load fonts");
 
 
@@ -300,7 +843,8 @@ fn main() {
         .set_blending(Some((Equation::Additive, Factor::SrcAlpha, Factor::SrcAlphaComplement)))
         .set_depth_test(None);
 
-    let mut text_tess;
+    let mut text_tess = None;
+    let mut text_verts = Vec::new();
 
     let canvas_sampler = Sampler {
         wrap_r : Wrap::ClampToEdge,
@@ -318,100 +862,201 @@ fn main() {
 
     let pattern = Canvas::new(width as usize, height as usize);
 
-    tex.upload(GenMipmaps::No, &pattern)
+    tex.upload_raw(GenMipmaps::No, pattern.data_raw())
         .expect("Cannot upload texture");
 
+    // Indexed canvases are drawn from their palette indices and their palette
+    let mut idx_tex : Texture<Dim2, NormR8UI> = Texture::new(&mut glfw, [width, height], 0, canvas_sampler)
+        .expect("Cannot create texture");
+    let pal_tex : Texture<Dim2, NormRGBA8UI> = Texture::new(&mut glfw, [canvas::MAX_INDEXED_COLORS as u32, 1], 0, canvas_sampler)
+        .expect("Cannot create texture");
+
 
     println!("Creating UI");
     let mut ui = create_ui();
 
     let mut palette = HashMap::new();
-    palette.insert(CharKeyMod::from("a"), (255, 0, 0, 255));
-    palette.insert(CharKeyMod::from("z"), (0, 255, 0, 255));
-    palette.insert(CharKeyMod::from("e"), (0, 0, 255, 255));
+    palette.insert(CharKeyMod::from("a"), Color::rgb(255, 0, 0));
+    palette.insert(CharKeyMod::from("z"), Color::rgb(0, 255, 0));
+    palette.insert(CharKeyMod::from("e"), Color::rgb(0, 0, 255));
 
     let mut state = UiState {
         filename: None,
         must_resize: false,
         scale: (1.0 / WIDTH, 1.0 / HEIGHT),
-        zoom: 1.0,
+        zoom: 8.0,
         canvas: pattern,
         center: (-8.0, -8.0),
         visual_type: VisualType::Square,
         palette,
+        last_color:None,
+        palette_panel:true,
+        blend:BlendMode::Normal,
+        palette_keys:KeySequence::from("azertyuiopqsdfghjklmwxcvbn1234567890").keys().to_vec(),
         window_size:(WIDTH, HEIGHT),
         selection:HashSet::new(),
         chunk_size:(4, 4),
+        frame_delay:100,
+        frame_delays:HashMap::new(),
+        frame_tags:Vec::new(),
         exploded:false,
+        grid:false,
+        chunk_grid:false,
+        grid_color:Color::rgba(64, 64, 64, 96),
+        chunk_grid_color:Color::rgba(255, 255, 255, 160),
+        screenshot:None,
+        message:None,
     };
 
     println!("Loading UI assets");
-    let img = open("selecteur.png").unwrap();
-    let raw : Vec<(u8, u8, u8, u8)> =
-        match img {
-            DynamicImage::ImageRgba8(img) => {
-                img
-                    .into_vec()
-                    .chunks(4)
-                    .map(|l| {
-                        match l {
-                            [r,b,g,a] => (*r,*b,*g,*a),
-                            _ => panic!("not normal"),
-                        }
-                    })
-                    .collect()
-            },
-            _ => { unimplemented!("Error while loading selection image") },
-        };
-    let tex_sel : Texture<Dim2, NormRGBA8UI> = Texture::new(&mut glfw, [256, 256], 0, text_sampler)
+    let mut sel_atlas = or_exit(load_image_asset(SELECTION_ATLAS));
+    let mut tex_sel : Texture<Dim2, NormRGBA8UI> = Texture::new(&mut glfw, [sel_atlas.width(), sel_atlas.height()], 0, text_sampler)
         .expect("Cannot create selection texture");
-    tex_sel.upload(GenMipmaps::No, raw.as_ref())
+    tex_sel.upload_raw(GenMipmaps::No, sel_atlas.as_raw())
         .expect("Cannot upload selection texture");
 
+    // Shaders and the selection atlas are reloaded when modified in the assets override directory
+    let mut watcher = assets::Watcher::new(&[
+        "src/canvas/normal.vert", "src/canvas/normal.frag", "src/canvas/indexed.frag",
+        "src/text/text.vert", "src/text/text.frag",
+        "src/ui/selection/selection.vert", "src/ui/selection/selection.frag",
+        "src/ui/background/background.vert", "src/ui/background/background.frag",
+        "src/ui/grid/grid.vert", "src/ui/grid/grid.frag",
+        SELECTION_ATLAS,
+    ]);
+
+    // Tessellations are kept from one frame to the next, and only rebuilt when their geometry
+    // changes.
+    let mut tess = None;
+    let mut canvas_geometry = None;
+    let mut select_tess = None;
+    let mut select_verts = Vec::new();
+
+    let mut palette_tess = None;
+    let mut palette_verts = Vec::new();
+    let mut bg_tess = None;
+    let mut bg_size = None;
+
     println!("Done");
 
     'main_loop: loop {
         if !ui.input(&mut glfw, &mut state) { break 'main_loop }
 
+        let snapped = state.canvas.take_snapped();
+        if snapped > 0 {
+            let max = canvas::MAX_INDEXED_COLORS;
+            state.message = Some(format!("palette full ({} colors): {} pixels painted with the closest color", max, snapped));
+        }
+
 
         if state.must_resize {
             framebuffer = glfw.back_buffer().unwrap();
             state.must_resize = false;
         }
 
-        tex = Texture::new(&mut glfw, [state.canvas.size.0 as u32, state.canvas.size.1 as u32], 0, canvas_sampler).unwrap();
-        tex.upload(GenMipmaps::No, state.canvas.as_ref()).expect("Cannot upload texture");
-
-        let mut verts = text.render_text(
-            format!("{:?}:{}", ui.get_mode(), ui.get_buffer()),
-            (HAlign::Left(0), VAlign::Bottom(0)),
-            state.window_size,
-            fid,
-            24.0);
-
-        verts.append(&mut
-            text.render_text(
-                format!("Exploded: {}, Chunk Size: {:?}, zoom: {}%", state.exploded, state.chunk_size, (state.zoom * 100.0) as i32),
-                (HAlign::Center, VAlign::Top(0)),
-                state.window_size,
-                fid,
-                24.0));
-                
-        verts.append(&mut
-            text.render_text(
-                match &state.filename {
-                    Some(filename) => format!("file: {}", filename),
-                    None => String::from("Unnamed Buffer"),
-                },
-                (HAlign::Right(0), VAlign::Bottom(0)),
-                state.window_size,
-                fid,
-                24.0));
-
-        text_tess = TessBuilder::new(&mut glfw)
-            .add_vertices(&verts[..])
-            .set_mode(Mode::Triangle)
-            .build().ok();
+        let changed = watcher.changed();
+        if !changed.is_empty() {
+            let msg = &mut state.message;
+            reload_program(&mut program, "src/canvas/normal.vert", "src/canvas/normal.frag", &changed, msg);
+            reload_program(&mut indexed_program, "src/canvas/normal.vert", "src/canvas/indexed.frag", &changed, msg);
+            reload_program(&mut text_program, "src/text/text.vert", "src/text/text.frag", &changed, msg);
+            reload_program(&mut select_program, "src/ui/selection/selection.vert", "src/ui/selection/selection.frag", &changed, msg);
+            reload_program(&mut bg_program, "src/ui/background/background.vert", "src/ui/background/background.frag", &changed, msg);
+            reload_program(&mut grid_program, "src/ui/grid/grid.vert", "src/ui/grid/grid.frag", &changed, msg);
+
+            if changed.iter().any(|c| c == SELECTION_ATLAS) {
+                match load_image_asset(SELECTION_ATLAS) {
+                    Ok(atlas) => {
+                        if tex_sel.size() != [atlas.width(), atlas.height()] {
+                            tex_sel = Texture::new(&mut glfw, [atlas.width(), atlas.height()], 0, text_sampler)
+                                .expect("Cannot create selection texture");
+                        }
+                        tex_sel.upload_raw(GenMipmaps::No, atlas.as_raw())
+                            .expect("Cannot upload selection texture");
+                        sel_atlas = atlas;
+                        *msg = Some(format!("reloaded {}", SELECTION_ATLAS));
+                    },
+                    Err(e) => *msg = Some(e),
+                }
+            }
+        }
+
+        // Only recreate the canvas texture when its size changed, otherwise upload the pixels
+        // modified since the last frame.
+        let canvas_size = [state.canvas.size.0 as u32, state.canvas.size.1 as u32];
+        if state.canvas.is_indexed() {
+            if idx_tex.size() != canvas_size {
+                idx_tex = Texture::new(&mut glfw, canvas_size, 0, canvas_sampler).unwrap();
+                state.canvas.take_dirty();
+                let indices = &state.canvas.indexed().unwrap().indices;
+                idx_tex.upload(GenMipmaps::No, indices).expect("Cannot upload texture");
+            } else if let Some((a, b)) = state.canvas.take_dirty() {
+                let offset = [a.0 as u32, a.1 as u32];
+                let size = [(b.0 - a.0 + 1) as u32, (b.1 - a.1 + 1) as u32];
+                idx_tex.upload_part(GenMipmaps::No, offset, size, &state.canvas.index_region(a, b))
+                    .expect("Cannot upload texture");
+            }
+
+            if state.canvas.take_palette_dirty() {
+                let mut colors = state.canvas.indexed().unwrap().palette.clone();
+                colors.resize(canvas::MAX_INDEXED_COLORS, Color::default());
+                pal_tex.upload_raw(GenMipmaps::No, color::as_bytes(&colors)).expect("Cannot upload palette");
+            }
+        } else if tex.size() != canvas_size {
+            tex = Texture::new(&mut glfw, canvas_size, 0, canvas_sampler).unwrap();
+            tex.upload_raw(GenMipmaps::No, state.canvas.data_raw()).expect("Cannot upload texture");
+            state.canvas.take_dirty();
+        } else if let Some((a, b)) = state.canvas.take_dirty() {
+            let offset = [a.0 as u32, a.1 as u32];
+            let size = [(b.0 - a.0 + 1) as u32, (b.1 - a.1 + 1) as u32];
+            tex.upload_part_raw(GenMipmaps::No, offset, size, color::as_bytes(&state.canvas.region(a, b)))
+                .expect("Cannot upload texture");
+        }
+
+        let mut lines = vec![
+            (format!("{:?}:{}", ui.get_mode(), ui.get_buffer()), (HAlign::Left(0), VAlign::Bottom(0))),
+            (format!("Exploded: {}, Chunk Size: {:?}, zoom: {}x, blend: {}", state.exploded, state.chunk_size, state.zoom, state.blend),
+                (HAlign::Center, VAlign::Top(0))),
+            (match &state.filename {
+                Some(filename) => format!("file: {}", filename),
+                None => String::from("Unnamed Buffer"),
+            }, (HAlign::Right(0), VAlign::Bottom(0))),
+        ];
+
+        if let Some(message) = &state.message {
+            lines.push((message.clone(), (HAlign::Left(0), VAlign::Bottom(24))));
+        }
+
+        let pal_verts = if state.palette_panel {
+            lines.append(&mut ui::palette::palette_labels(&state));
+            ui::palette::render_palette(&state)
+        } else {
+            Vec::new()
+        };
+        if pal_verts != palette_verts {
+            palette_tess = TessBuilder::new(&mut glfw)
+                .add_vertices(&pal_verts)
+                .set_mode(Mode::Triangle)
+                .build()
+                .ok();
+            palette_verts = pal_verts;
+        }
+
+        let mut verts = Vec::new();
+        for (line, align) in lines {
+            if let Err(e) = text.load_glyphs(&mut glfw, &line, fid) {
+                state.message = Some(format!("cannot update the font atlas: {}", e));
+            }
+            verts.append(&mut text.render_text(line, align, state.window_size, fid, 24.0));
+        }
+
+        if verts != text_verts {
+            text_tess = TessBuilder::new(&mut glfw)
+                .add_vertices(&verts[..])
+                .set_mode(Mode::Triangle)
+                .build().ok();
+            text_verts = verts;
+        }
 
         let set =
             if ui.get_mode() == ui::Mode::Visual {
@@ -428,77 +1073,123 @@ fn main() {
                 state.selection.clone()
             };
 
-        let select_tess = TessBuilder::new(&mut glfw)
-            .add_vertices(&state.render_selection(&set))
-            .set_mode(Mode::Triangle)
-            .build()
-            .unwrap();
+        let sel_verts = state.render_selection(&set);
+        if sel_verts != select_verts {
+            select_tess = TessBuilder::new(&mut glfw)
+                .add_vertices(&sel_verts)
+                .set_mode(Mode::Triangle)
+                .build()
+                .ok();
+            select_verts = sel_verts;
+        }
 
+        // The canvas geometry only depends on its size and on the chunks layout
+        let geometry = (state.canvas.size(), state.chunk_size, state.exploded);
+        if canvas_geometry != Some(geometry) {
+            tess = TessBuilder::new(&mut glfw)
+                .add_vertices(state.render_canvas())
+                .set_mode(Mode::Triangle)
+                .build()
+                .ok();
+            canvas_geometry = Some(geometry);
+        }
 
-        let tri_vert = state.render_canvas();
-        let tess = TessBuilder::new(&mut glfw)
-                .add_vertices(tri_vert)
+        if bg_size != Some(state.window_size) {
+            bg_tess = TessBuilder::new(&mut glfw)
+                .add_vertices(&ui::background::render_background(state.window_size))
                 .set_mode(Mode::Triangle)
                 .build()
-                .unwrap();
+                .ok();
+            bg_size = Some(state.window_size);
+        }
 
-        let bg_tess = TessBuilder::new(&mut glfw)
-            .add_vertices(&ui::background::render_background(state.window_size))
-            .set_mode(Mode::Triangle)
-            .build()
-            .unwrap();
+        if let Some(fname) = state.screenshot.take() {
+            let res = take_screenshot(&state, &text_verts, &select_verts, &palette_verts, &text.glyphs.image(), &sel_atlas).save(&fname);
+            state.message = Some(match res {
+                Ok(()) => format!("screenshot saved to {}", fname),
+                Err(e) => format!("{}: {}", fname, e),
+            });
+        }
 
         // Draw
         glfw.pipeline_builder().pipeline(&framebuffer, &pipestate, |pipeline, mut shd_gate| {
             let drawing_buffer = pipeline.bind_texture(&tex);
+            let index_buffer = pipeline.bind_texture(&idx_tex);
+            let palette_buffer = pipeline.bind_texture(&pal_tex);
             let font_atlas = pipeline.bind_texture(&text.atlas);
             let select_atlas = pipeline.bind_texture(&tex_sel);
 
-            let text_view = {
-                let center_x = state.window_size.0 * 0.5;
-                let center_y = state.window_size.1 * 0.5;
-                let scale_x = state.scale.0 * 2.0;
-                let scale_y = -state.scale.1 * 2.0;
-
-                to_raw(scale(scale_x, scale_y) * translate(-center_x, -center_y - 10.0))
-            };
+            let text_view = to_raw(state.text_view());
 
-            let canvas_view = {
-                let scale_x = state.scale.0 * (width as f32) * state.zoom;
-                let scale_y = -state.scale.1 * (height as f32) * state.zoom;
-
-                to_raw(scale(scale_x, scale_y) * translate(state.center.0, state.center.1))
-            };
+            let canvas_view = to_raw(state.canvas_view());
 
             // render background
-            shd_gate.shade(&bg_program, |_, mut rdr_gate| {
-                rdr_gate.render(&render_state, |mut tess_gate| tess_gate.render(&bg_tess));
-            });
+            if let Some(bg_tess) = &bg_tess {
+                shd_gate.shade(&bg_program, |_, mut rdr_gate| {
+                    rdr_gate.render(&render_state, |mut tess_gate| tess_gate.render(bg_tess));
+                });
+            }
 
             // render canvas
-            shd_gate.shade(&program, |iface, mut rdr_gate| {
-                iface.query().ask("tex").unwrap().update(&drawing_buffer);
-                iface.query().ask("view").unwrap().update(canvas_view);
+            if let (Some(tess), true) = (&tess, state.canvas.is_indexed()) {
+                shd_gate.shade(&indexed_program, |iface, mut rdr_gate| {
+                    iface.query().ask("tex").unwrap().update(&index_buffer);
+                    iface.query().ask("palette").unwrap().update(&palette_buffer);
+                    iface.query().ask("view").unwrap().update(canvas_view);
 
-                rdr_gate.render(&render_state, |mut tess_gate| tess_gate.render(&tess) );
-            });
+                    rdr_gate.render(&render_state, |mut tess_gate| tess_gate.render(tess) );
+                });
+            } else if let Some(tess) = &tess {
+                shd_gate.shade(&program, |iface, mut rdr_gate| {
+                    iface.query().ask("tex").unwrap().update(&drawing_buffer);
+                    iface.query().ask("view").unwrap().update(canvas_view);
+
+                    rdr_gate.render(&render_state, |mut tess_gate| tess_gate.render(tess) );
+                });
+            }
+
+            // render pixel and chunk grids over the canvas
+            let show_grid = state.grid && state.zoom >= GRID_MIN_ZOOM;
+            if let (Some(tess), true) = (&tess, show_grid || state.chunk_grid) {
+                shd_gate.shade(&grid_program, |iface, mut rdr_gate| {
+                    let uniform = iface.query();
+                    let (cw, ch) = state.canvas.size();
+                    let (chw, chh) = state.chunk_size;
+                    uniform.ask("view").unwrap().update(canvas_view);
+                    uniform.ask("canvas_size").unwrap().update([cw as f32, ch as f32]);
+                    uniform.ask("chunk_size").unwrap().update([chw as f32, chh as f32]);
+                    uniform.ask("grid_color").unwrap().update(uniform_color(state.grid_color, show_grid));
+                    uniform.ask("chunk_color").unwrap().update(uniform_color(state.chunk_grid_color, state.chunk_grid));
+
+                    rdr_gate.render(&render_state, |mut tess_gate| tess_gate.render(tess) );
+                });
+            }
 
             // render selector
-            shd_gate.shade(&select_program, |iface, mut rdr_gate| {
-                iface.query().ask("tex").unwrap().update(&select_atlas);
-                iface.query().ask("view").unwrap().update(canvas_view);
+            if let Some(select_tess) = &select_tess {
+                shd_gate.shade(&select_program, |iface, mut rdr_gate| {
+                    iface.query().ask("tex").unwrap().update(&select_atlas);
+                    iface.query().ask("view").unwrap().update(canvas_view);
 
-                rdr_gate.render(&render_state, |mut tess_gate| tess_gate.render(&select_tess) );
-            });
+                    rdr_gate.render(&render_state, |mut tess_gate| tess_gate.render(select_tess) );
+                });
+            }
+
+            // render palette panel
+            if let Some(palette_tess) = &palette_tess {
+                shd_gate.shade(&bg_program, |_, mut rdr_gate| {
+                    rdr_gate.render(&render_state, |mut tess_gate| tess_gate.render(palette_tess));
+                });
+            }
 
             // render ui text
-            text_tess.map(|text_tess| {
+            text_tess.as_ref().map(|text_tess| {
                 shd_gate.shade(&text_program, |iface, mut rdr_gate| {
                     let uniform = iface.query();
                     uniform.ask("tex").unwrap().update(&font_atlas);
                     uniform.ask("view").unwrap().update(text_view);
 
-                    rdr_gate.render(&render_state, |mut tess_gate| tess_gate.render(&text_tess) );
+                    rdr_gate.render(&render_state, |mut tess_gate| tess_gate.render(text_tess) );
                 });
             });
         });
@@ -506,4 +1197,8 @@ fn main() {
         // display
         glfw.swap_buffers();
     }
+
+    if let Err(e) = text.save_cache() {
+        eprintln!("Cannot cache font atlas: {}", e);
+    }
 }
@@ -0,0 +1,217 @@
+use std::{fs, path::Path};
+
+use crate::color::{self, Color};
+
+/// Palette file formats, as found on Lospec.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    /// GIMP palette (`.gpl`): a header, then a `r g b name` line per color.
+    Gpl,
+    /// A `rrggbb` line per color (`.hex`).
+    Hex,
+    /// JASC-PAL (`.pal`): a header and color count, then a `r g b` line per color.
+    JascPal,
+    /// Paint.NET palette (`.txt`): an `aarrggbb` line per color, and `;` comments.
+    PaintNet,
+}
+
+impl Format {
+    /// Guess the format of a file from its extension.
+    pub fn from_path(path:&Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_lowercase();
+        match ext.as_str() {
+            "gpl" => Some(Format::Gpl),
+            "hex" => Some(Format::Hex),
+            "pal" => Some(Format::JascPal),
+            "txt" => Some(Format::PaintNet),
+            _ => None,
+        }
+    }
+}
+
+/// Parse the content of a palette file. Colors have no alpha in the formats other than Paint.NET,
+/// except for hex files, where an `rrggbbaa` line is accepted too.
+pub fn parse(format:Format, content:&str) -> Result<Vec<Color>, String> {
+    let mut lines = content
+        .lines()
+        .map(str::trim)
+        .enumerate()
+        .map(|(i, l)| (i + 1, l))
+        .filter(|(_, l)| !l.is_empty());
+
+    let error = |line:usize, msg:&str| format!("line {}: {}", line, msg);
+
+    match format {
+        Format::Gpl => {
+            match lines.next() {
+                Some((_, "GIMP Palette")) => (),
+                _ => return Err(error(1, "missing GIMP Palette header")),
+            }
+
+            lines
+                .filter(|(_, l)| !l.starts_with('#') && !l.starts_with("Name:") && !l.starts_with("Columns:"))
+                .map(|(i, l)| {
+                    let c : Vec<u8> = l.split_whitespace().take(3).filter_map(|v| v.parse().ok()).collect();
+                    match c[..] {
+                        [r, g, b] => Ok(Color::rgb(r, g, b)),
+                        _ => Err(error(i, "expected r g b")),
+                    }
+                })
+                .collect()
+        },
+        Format::Hex => {
+            lines
+                .map(|(i, l)| color::parse_hex(l.trim_start_matches('#')).ok_or_else(|| error(i, "expected rrggbb")))
+                .collect()
+        },
+        Format::JascPal => {
+            match (lines.next(), lines.next()) {
+                (Some((_, "JASC-PAL")), Some((_, "0100"))) => (),
+                _ => return Err(error(1, "missing JASC-PAL header")),
+            }
+
+            let (i, count) = lines.next().ok_or_else(|| error(3, "missing color count"))?;
+            let count : usize = count.parse().map_err(|_| error(i, "invalid color count"))?;
+
+            let colors = lines
+                .map(|(i, l)| {
+                    let c : Vec<u8> = l.split_whitespace().filter_map(|v| v.parse().ok()).collect();
+                    match c[..] {
+                        [r, g, b] => Ok(Color::rgb(r, g, b)),
+                        _ => Err(error(i, "expected r g b")),
+                    }
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            if colors.len() != count {
+                return Err(format!("expected {} colors, found {}", count, colors.len()))
+            }
+            Ok(colors)
+        },
+        Format::PaintNet => {
+            lines
+                .filter(|(_, l)| !l.starts_with(';'))
+                .map(|(i, l)| {
+                    let argb = (l.len() == 8).then(|| u32::from_str_radix(l, 16).ok()).flatten();
+                    let [a, r, g, b] = argb.ok_or_else(|| error(i, "expected aarrggbb"))?.to_be_bytes();
+                    Ok(Color::rgba(r, g, b, a))
+                })
+                .collect()
+        },
+    }
+}
+
+/// Write colors in a palette file format. Formats without alpha drop it, except hex files, where
+/// translucent colors are written as `rrggbbaa`.
+pub fn write(format:Format, name:&str, colors:&[Color]) -> String {
+    let mut ret = String::new();
+
+    match format {
+        Format::Gpl => {
+            ret.push_str(&format!("GIMP Palette\nName: {}\n#\n", name));
+            for Color { r, g, b, .. } in colors {
+                ret.push_str(&format!("{:3} {:3} {:3}\t{:02x}{:02x}{:02x}\n", r, g, b, r, g, b));
+            }
+        },
+        Format::Hex => {
+            for color in colors {
+                ret.push_str(&format!("{}\n", color.to_string().trim_start_matches('#')));
+            }
+        },
+        Format::JascPal => {
+            ret.push_str(&format!("JASC-PAL\n0100\n{}\n", colors.len()));
+            for Color { r, g, b, .. } in colors {
+                ret.push_str(&format!("{} {} {}\n", r, g, b));
+            }
+        },
+        Format::PaintNet => {
+            ret.push_str(&format!(";paint.net Palette File\n;Palette Name: {}\n;Colors: {}\n", name, colors.len()));
+            for Color { r, g, b, a } in colors {
+                ret.push_str(&format!("{:02X}{:02X}{:02X}{:02X}\n", a, r, g, b));
+            }
+        },
+    }
+
+    ret
+}
+
+fn format_of(path:&Path) -> Result<Format, String> {
+    Format::from_path(path).ok_or_else(|| format!("{}: unknown palette format", path.display()))
+}
+
+pub fn load<P:AsRef<Path>>(path:P) -> Result<Vec<Color>, String> {
+    let path = path.as_ref();
+    let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    parse(format_of(path)?, &content).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Save colors to a palette file, in the format given by its extension. The palette is named
+/// after the file.
+pub fn save<P:AsRef<Path>>(path:P, colors:&[Color]) -> Result<(), String> {
+    let path = path.as_ref();
+    let name = path.file_stem().map_or(String::new(), |s| s.to_string_lossy().into_owned());
+
+    fs::write(path, write(format_of(path)?, &name, colors)).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMATS : & 'static [Format] = &[Format::Gpl, Format::Hex, Format::JascPal, Format::PaintNet];
+
+    #[test]
+    fn round_trip() {
+        let colors = [Color::rgb(0, 0, 0), Color::rgb(255, 136, 0), Color::rgb(18, 52, 86)];
+        for &format in FORMATS {
+            assert_eq!(parse(format, &write(format, "test", &colors)), Ok(colors.to_vec()), "{:?}", format);
+        }
+
+        // alpha is kept by the formats which have it
+        let colors = [Color::rgba(255, 136, 0, 128), Color::rgb(1, 2, 3)];
+        for &format in &[Format::Hex, Format::PaintNet] {
+            assert_eq!(parse(format, &write(format, "test", &colors)), Ok(colors.to_vec()), "{:?}", format);
+        }
+        let opaque = vec![Color::rgb(255, 136, 0), Color::rgb(1, 2, 3)];
+        assert_eq!(parse(Format::Gpl, &write(Format::Gpl, "test", &colors)), Ok(opaque));
+    }
+
+    #[test]
+    fn gpl() {
+        let content = "GIMP Palette\nName: test\nColumns: 4\n# comment\n\n  0  64 255\tblue\n255 255 255\n";
+        assert_eq!(parse(Format::Gpl, content), Ok(vec![Color::rgb(0, 64, 255), Color::WHITE]));
+
+        assert_eq!(parse(Format::Gpl, "Name: test\n0 0 0\n"), Err(String::from("line 1: missing GIMP Palette header")));
+        assert_eq!(parse(Format::Gpl, "GIMP Palette\n0 0\n"), Err(String::from("line 2: expected r g b")));
+        assert_eq!(parse(Format::Gpl, "GIMP Palette\n0 0 256\n"), Err(String::from("line 2: expected r g b")));
+    }
+
+    #[test]
+    fn jasc_pal() {
+        assert_eq!(parse(Format::JascPal, "JASC-PAL\n0100\n1\n1 2 3\n"), Ok(vec![Color::rgb(1, 2, 3)]));
+
+        assert_eq!(parse(Format::JascPal, "JASC-PAL\n0200\n1\n1 2 3\n"), Err(String::from("line 1: missing JASC-PAL header")));
+        assert_eq!(parse(Format::JascPal, "GIMP Palette\n0100\n1\n1 2 3\n"), Err(String::from("line 1: missing JASC-PAL header")));
+        assert_eq!(parse(Format::JascPal, "JASC-PAL\n0100\n"), Err(String::from("line 3: missing color count")));
+        assert_eq!(parse(Format::JascPal, "JASC-PAL\n0100\nten\n"), Err(String::from("line 3: invalid color count")));
+        assert_eq!(parse(Format::JascPal, "JASC-PAL\n0100\n2\n1 2 3\n"), Err(String::from("expected 2 colors, found 1")));
+    }
+
+    #[test]
+    fn hex_and_paint_net() {
+        assert_eq!(parse(Format::Hex, "ff8800\n#00000080\n"), Ok(vec![Color::rgb(255, 136, 0), Color::rgba(0, 0, 0, 128)]));
+        assert_eq!(parse(Format::Hex, "ff8800\nff88000\n"), Err(String::from("line 2: expected rrggbb")));
+
+        assert_eq!(parse(Format::PaintNet, ";comment\n80FF8800\n"), Ok(vec![Color::rgba(255, 136, 0, 128)]));
+        assert_eq!(parse(Format::PaintNet, "FF8800\n"), Err(String::from("line 1: expected aarrggbb")));
+    }
+
+    #[test]
+    fn formats_from_extensions() {
+        assert_eq!(Format::from_path(Path::new("a/b.GPL")), Some(Format::Gpl));
+        assert_eq!(Format::from_path(Path::new("b.pal")), Some(Format::JascPal));
+        assert_eq!(Format::from_path(Path::new("b.png")), None);
+        assert_eq!(Format::from_path(Path::new("pal")), None);
+    }
+}
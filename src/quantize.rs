@@ -0,0 +1,299 @@
+use std::{collections::HashMap, str::FromStr};
+
+use crate::{canvas::Canvas, color::{Color, Metric}};
+
+/// How the error between a pixel and the closest palette color is spread over the image.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Dither {
+    None,
+    /// 4x4 Bayer matrix.
+    Ordered,
+    FloydSteinberg,
+}
+
+impl FromStr for Dither {
+    type Err = String;
+
+    fn from_str(s:&str) -> Result<Self, String> {
+        match s {
+            "none" => Ok(Dither::None),
+            "ordered" => Ok(Dither::Ordered),
+            "fs" | "floydsteinberg" => Ok(Dither::FloydSteinberg),
+            _ => Err(format!("unknown dithering: {}", s)),
+        }
+    }
+}
+
+const BAYER : [[f32; 4]; 4] = [
+    [ 0.0,  8.0,  2.0, 10.0],
+    [12.0,  4.0, 14.0,  6.0],
+    [ 3.0, 11.0,  1.0,  9.0],
+    [15.0,  7.0, 13.0,  5.0],
+];
+
+// amplitude of the ordered dithering offsets, in 0..255 channel units
+const BAYER_SPREAD : f32 = 32.0;
+
+fn channels(c:Color) -> [f32; 4] {
+    [c.r as f32, c.g as f32, c.b as f32, c.a as f32]
+}
+
+fn from_channels(c:[f32; 4]) -> Color {
+    let ch = |v:f32| v.max(0.0).min(255.0).round() as u8;
+    Color::rgba(ch(c[0]), ch(c[1]), ch(c[2]), ch(c[3]))
+}
+
+/// Reduce colors, weighted by their number of pixels, to at most `n` colors with the median cut
+/// algorithm: the set of colors is split in two at the median of its widest channel, until there
+/// are `n` sets, and each set gives its weighted average color.
+pub fn median_cut(colors:&[(Color, usize)], n:usize) -> Vec<Color> {
+    let mut boxes : Vec<Vec<(Color, usize)>> = vec![colors.to_vec()];
+
+    while boxes.len() < n {
+        // widest channel of each box which can be split
+        let widest = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.len() > 1)
+            .flat_map(|(i, b)| (0..4).map(move |ch| {
+                let values = b.iter().map(|(c, _)| channels(*c)[ch] as u32);
+                (values.clone().max().unwrap() - values.min().unwrap(), i, ch)
+            }))
+            .max();
+
+        let (_, i, ch) = match widest {
+            Some(w) => w,
+            None => break,
+        };
+
+        let mut b = boxes.swap_remove(i);
+        b.sort_by_key(|(c, _)| channels(*c)[ch] as u32);
+
+        // split where half of the pixels are on each side, keeping a color in each box
+        let total : usize = b.iter().map(|(_, n)| n).sum();
+        let mut acc = 0;
+        let mut split = b.iter().position(|(_, n)| { acc += n; acc * 2 >= total }).unwrap() + 1;
+        split = split.max(1).min(b.len() - 1);
+
+        let rest = b.split_off(split);
+        boxes.push(b);
+        boxes.push(rest);
+    }
+
+    boxes
+        .iter()
+        .filter(|b| !b.is_empty())
+        .map(|b| {
+            let total : usize = b.iter().map(|(_, n)| n).sum();
+            let mut sum = [0.0; 4];
+            for (c, n) in b {
+                for (s, v) in sum.iter_mut().zip(channels(*c).iter()) {
+                    *s += v * *n as f32;
+                }
+            }
+            from_channels([sum[0] / total as f32, sum[1] / total as f32, sum[2] / total as f32, sum[3] / total as f32])
+        })
+        .collect()
+}
+
+/// Replace the given pixels by their closest color in the palette, and return the number of
+/// pixels which changed. Errors are only diffused to the given pixels.
+pub fn apply(canvas:&mut Canvas, pixels:&[(usize, usize)], palette:&[Color], dither:Dither) -> usize {
+    let (w, h) = canvas.size();
+    let mut errors = vec![[0.0f32; 4]; w * h];
+    let mut inside = vec![false; w * h];
+    for &(x, y) in pixels {
+        inside[y * w + x] = true;
+    }
+
+    let mut pixels = pixels.to_vec();
+    pixels.sort_by_key(|&(x, y)| (y, x));
+
+    let mut changed = 0;
+    for (x, y) in pixels {
+        let old = canvas.get_pixel_color(x, y);
+        let mut target = channels(old);
+
+        match dither {
+            Dither::None => (),
+            Dither::Ordered => {
+                let offset = (BAYER[y % 4][x % 4] + 0.5) / 16.0 - 0.5;
+                for v in target.iter_mut().take(3) {
+                    *v += offset * BAYER_SPREAD;
+                }
+            },
+            Dither::FloydSteinberg => {
+                for (v, e) in target.iter_mut().zip(errors[y * w + x].iter()) {
+                    *v += e;
+                }
+            },
+        }
+
+        let new = Metric::Rgb.nearest(palette, from_channels(target)).expect("empty palette");
+
+        if dither == Dither::FloydSteinberg {
+            let new_channels = channels(new);
+            let spread = [(1, 0, 7.0), (-1, 1, 3.0), (0, 1, 5.0), (1, 1, 1.0)];
+            for (dx, dy, weight) in spread.iter() {
+                let (nx, ny) = (x as isize + dx, y as isize + dy);
+                if nx < 0 || nx >= w as isize || ny >= h as isize || !inside[ny as usize * w + nx as usize] {
+                    continue
+                }
+                let e = &mut errors[ny as usize * w + nx as usize];
+                for ch in 0..4 {
+                    e[ch] += (target[ch] - new_channels[ch]) * weight / 16.0;
+                }
+            }
+        }
+
+        if new != old {
+            canvas.set_pixel_color(x, y, new);
+            changed += 1;
+        }
+    }
+
+    changed
+}
+
+/// Replace each of the given pixels by its color in `table`, or by the closest palette color if
+/// it isn't in the table, and return the number of pixels which changed.
+pub fn remap(canvas:&mut Canvas, pixels:&[(usize, usize)], palette:&[Color], table:&HashMap<Color, Color>,
+             metric:Metric) -> usize
+{
+    // images have far less colors than pixels
+    let mut cache = table.clone();
+    let mut changed = 0;
+
+    for &(x, y) in pixels {
+        let old = canvas.get_pixel_color(x, y);
+        let new = match cache.get(&old) {
+            Some(new) => *new,
+            None => {
+                let new = metric.nearest(palette, old).unwrap_or(old);
+                cache.insert(old, new);
+                new
+            },
+        };
+
+        if new != old {
+            canvas.set_pixel_color(x, y, new);
+            changed += 1;
+        }
+    }
+
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 16x16 canvas with a gradient of many colors.
+    fn gradient() -> Canvas {
+        let mut canvas = Canvas::new(16, 16);
+        for y in 0..16 {
+            for x in 0..16 {
+                canvas.set_pixel_color(x, y, Color::rgb(x as u8 * 16, y as u8 * 16, 128));
+            }
+        }
+        canvas
+    }
+
+    fn all_pixels(canvas:&Canvas) -> Vec<(usize, usize)> {
+        let (w, h) = canvas.size();
+        (0..h).flat_map(|y| (0..w).map(move |x| (x, y))).collect()
+    }
+
+    fn colors(canvas:&Canvas) -> Vec<(Color, usize)> {
+        let mut counts = HashMap::new();
+        for c in &canvas.data {
+            *counts.entry(*c).or_insert(0) += 1;
+        }
+        counts.into_iter().collect()
+    }
+
+    #[test]
+    fn median_cut_color_count() {
+        let colors = colors(&gradient());
+        for &n in &[1, 2, 5, 16, 255] {
+            let palette = median_cut(&colors, n);
+            assert_eq!(palette.len(), n.min(colors.len()));
+        }
+
+        // a single box gives the weighted average
+        let palette = median_cut(&[(Color::rgb(0, 0, 0), 3), (Color::rgb(100, 200, 40), 1)], 1);
+        assert_eq!(palette, vec![Color::rgb(25, 50, 10)]);
+    }
+
+    #[test]
+    fn few_colors_are_unchanged() {
+        let (a, b, c) = (Color::rgb(255, 0, 0), Color::rgba(0, 0, 255, 128), Color::rgb(10, 20, 30));
+        let mut canvas = Canvas::new(3, 2);
+        for (i, color) in [a, b, c, c, a, a].iter().enumerate() {
+            canvas.set_pixel_color(i % 3, i / 3, *color);
+        }
+        let data = canvas.data.clone();
+
+        let mut palette = median_cut(&colors(&canvas), 4);
+        palette.sort_by_key(|c| <(u8, u8, u8, u8)>::from(*c));
+        assert_eq!(palette, vec![Color::rgba(0, 0, 255, 128), Color::rgb(10, 20, 30), Color::rgb(255, 0, 0)]);
+
+        let pixels = all_pixels(&canvas);
+        for &dither in &[Dither::None, Dither::FloydSteinberg] {
+            assert_eq!(apply(&mut canvas, &pixels, &palette, dither), 0);
+            assert_eq!(canvas.data, data);
+        }
+    }
+
+    #[test]
+    fn dithering_uses_the_palette_inside_the_pixels() {
+        let palette = [Color::BLACK, Color::WHITE, Color::rgb(0, 0, 255)];
+        let original = gradient();
+        // the left half of the canvas
+        let pixels : Vec<_> = all_pixels(&original).into_iter().filter(|(x, _)| *x < 8).collect();
+
+        for &dither in &[Dither::None, Dither::Ordered, Dither::FloydSteinberg] {
+            let mut canvas = gradient();
+            let changed = apply(&mut canvas, &pixels, &palette, dither);
+
+            let mut count = 0;
+            for (i, (new, old)) in canvas.data.iter().zip(original.data.iter()).enumerate() {
+                if i % 16 < 8 {
+                    assert!(palette.contains(new), "{:?}: {:?} is not in the palette", dither, new);
+                } else {
+                    assert_eq!(new, old, "{:?}: pixel {} changed", dither, i);
+                }
+                count += (new != old) as usize;
+            }
+            assert_eq!(changed, count);
+        }
+    }
+
+    #[test]
+    fn remap_table_before_nearest() {
+        let (red, dark_red, blue) = (Color::rgb(255, 0, 0), Color::rgb(128, 0, 0), Color::rgb(0, 0, 255));
+        let mut canvas = Canvas::new(4, 1);
+        for (x, color) in [red, dark_red, blue, red].iter().enumerate() {
+            canvas.set_pixel_color(x, 0, *color);
+        }
+
+        // red would be kept as the nearest palette color, but the table maps it to blue
+        let palette = [red, blue];
+        let table : HashMap<Color, Color> = [(red, blue)].iter().cloned().collect();
+        let pixels = all_pixels(&canvas);
+        let changed = remap(&mut canvas, &pixels, &palette, &table, Metric::Rgb);
+        assert_eq!(canvas.data, vec![blue, red, blue, blue]);
+        assert_eq!(changed, 3);
+
+        // only the given pixels are remapped, and unchanged pixels are not counted
+        let table = [(blue, dark_red)].iter().cloned().collect();
+        let changed = remap(&mut canvas, &[(0, 0), (1, 0), (2, 0)], &palette, &table, Metric::Rgb);
+        assert_eq!(canvas.data, vec![dark_red, red, dark_red, blue]);
+        assert_eq!(changed, 2);
+
+        // without a palette, only the table applies
+        let table = [(red, blue)].iter().cloned().collect();
+        assert_eq!(remap(&mut canvas, &pixels, &[], &table, Metric::Oklab), 1);
+        assert_eq!(canvas.data, vec![dark_red, blue, dark_red, blue]);
+    }
+}
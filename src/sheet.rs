@@ -0,0 +1,355 @@
+use std::{path::Path, str::FromStr};
+
+use serde::{Serialize, Serializer};
+
+use crate::{color::Color, export::AnimFrame};
+
+/// How the frames are placed on the sheet.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Layout {
+    /// Frames are kept whole, in cells of a grid, row by row.
+    Grid,
+    /// Transparent borders are trimmed, and frames are packed on shelves, tallest first.
+    Tight,
+}
+
+impl FromStr for Layout {
+    type Err = String;
+
+    fn from_str(s:&str) -> Result<Self, String> {
+        match s {
+            "grid" => Ok(Layout::Grid),
+            "tight" => Ok(Layout::Tight),
+            _ => Err(format!("unknown layout: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Options {
+    pub layout:Layout,
+    /// Transparent pixels between the frames, and around the sheet.
+    pub padding:usize,
+    /// Number of times the border pixels of each frame are repeated around it, so that filtering
+    /// doesn't bleed from the neighbouring frames.
+    pub extrude:usize,
+    /// Number of frames per row of the grid, by default so that the sheet is roughly square.
+    pub columns:Option<usize>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options { layout:Layout::Grid, padding:0, extrude:0, columns:None }
+    }
+}
+
+impl Options {
+    /// Whether a `name=value` argument of `:export` is a sheet option.
+    pub fn is_option(arg:&str) -> bool {
+        ["layout=", "padding=", "extrude=", "columns="].iter().any(|o| arg.starts_with(o))
+    }
+
+    pub fn set(&mut self, name:&str, value:&str) -> Result<(), String> {
+        let number = |v:&str| v.parse::<usize>().map_err(|_| format!("invalid {}: {}", name, v));
+
+        match name {
+            "layout" => self.layout = value.parse()?,
+            "padding" => self.padding = number(value)?,
+            "extrude" => self.extrude = number(value)?,
+            "columns" => self.columns = Some(number(value)?).filter(|c| *c > 0),
+            _ => return Err(format!("unknown option: {}", name)),
+        }
+        Ok(())
+    }
+}
+
+/// A named range of frames, as Aseprite tags animations.
+#[derive(Clone, Debug, Serialize)]
+pub struct FrameTag {
+    pub name:String,
+    /// First and last frames, inclusive.
+    pub from:usize,
+    pub to:usize,
+    /// `forward`, `reverse` or `pingpong`.
+    pub direction:String,
+}
+
+#[derive(Clone, Copy, Serialize)]
+struct Rect { x:usize, y:usize, w:usize, h:usize }
+
+#[derive(Clone, Copy, Serialize)]
+struct Size { w:usize, h:usize }
+
+/// A frame in the TexturePacker/Aseprite "hash" format.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SheetFrame {
+    /// Position of the frame on the sheet, without its extrusion.
+    frame:Rect,
+    rotated:bool,
+    trimmed:bool,
+    /// Part of the frame which is on the sheet, relative to the untrimmed frame.
+    sprite_source_size:Rect,
+    source_size:Size,
+    duration:u32,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Meta {
+    app:& 'static str,
+    version:& 'static str,
+    image:String,
+    format:& 'static str,
+    size:Size,
+    scale:& 'static str,
+    frame_tags:Vec<FrameTag>,
+}
+
+#[derive(Serialize)]
+struct Sheet {
+    #[serde(serialize_with = "ordered_map")]
+    frames:Vec<(String, SheetFrame)>,
+    meta:Meta,
+}
+
+/// Serialize the frames as a JSON object, keeping them in order.
+fn ordered_map<S:Serializer>(frames:&[(String, SheetFrame)], s:S) -> Result<S::Ok, S::Error> {
+    s.collect_map(frames.iter().map(|(k, v)| (k, v)))
+}
+
+/// Smallest rectangle containing the non transparent pixels of a frame, or a transparent pixel
+/// if there are none.
+fn trim(pixels:&[Color], (w, h):(usize, usize)) -> Rect {
+    let opaque = |x:usize, y:usize| pixels[y * w + x].a != 0;
+    let rows : Vec<usize> = (0..h).filter(|&y| (0..w).any(|x| opaque(x, y))).collect();
+    let cols : Vec<usize> = (0..w).filter(|&x| (0..h).any(|y| opaque(x, y))).collect();
+
+    match (rows.first(), rows.last(), cols.first(), cols.last()) {
+        (Some(&y1), Some(&y2), Some(&x1), Some(&x2)) => Rect { x:x1, y:y1, w:x2 - x1 + 1, h:y2 - y1 + 1 },
+        _ => Rect { x:0, y:0, w:1, h:1 },
+    }
+}
+
+/// Place rectangles of the given sizes, returning their topleft corners and the size of the sheet.
+fn place(sizes:&[Size], options:&Options) -> (Vec<(usize, usize)>, Size) {
+    let pad = options.padding;
+    let mut ret = vec![(0, 0); sizes.len()];
+    let (mut sheet_w, mut sheet_h) = (0, 0);
+
+    match options.layout {
+        Layout::Grid => {
+            let cell = Size {
+                w:sizes.iter().map(|s| s.w).max().unwrap_or(0),
+                h:sizes.iter().map(|s| s.h).max().unwrap_or(0),
+            };
+            let cols = options.columns.unwrap_or_else(|| (sizes.len() as f32).sqrt().ceil() as usize).max(1);
+
+            for (i, pos) in ret.iter_mut().enumerate() {
+                *pos = (pad + (i % cols) * (cell.w + pad), pad + (i / cols) * (cell.h + pad));
+                sheet_w = sheet_w.max(pos.0 + cell.w + pad);
+                sheet_h = sheet_h.max(pos.1 + cell.h + pad);
+            }
+        },
+        Layout::Tight => {
+            // shelves as wide as a square sheet would be
+            let area : usize = sizes.iter().map(|s| (s.w + pad) * (s.h + pad)).sum();
+            let widest = sizes.iter().map(|s| s.w).max().unwrap_or(0);
+            let max_w = ((area as f32).sqrt().ceil() as usize).max(widest + pad * 2);
+
+            let mut order : Vec<usize> = (0..sizes.len()).collect();
+            order.sort_by_key(|&i| std::cmp::Reverse(sizes[i].h));
+
+            let (mut x, mut y, mut shelf_h) = (pad, pad, 0);
+            for i in order {
+                let s = sizes[i];
+                if x + s.w + pad > max_w && x > pad {
+                    x = pad;
+                    y += shelf_h + pad;
+                    shelf_h = 0;
+                }
+
+                ret[i] = (x, y);
+                x += s.w + pad;
+                shelf_h = shelf_h.max(s.h);
+                sheet_w = sheet_w.max(x);
+                sheet_h = sheet_h.max(y + s.h + pad);
+            }
+        },
+    }
+
+    (ret, Size { w:sheet_w, h:sheet_h })
+}
+
+/// Pack frames of the given size on a sheet, write it as an image, and write its description next
+/// to it, with a `.json` extension. Frames are named after the image file and their index.
+pub fn write<P:AsRef<Path>>(path:P, (w, h):(usize, usize), frames:&[AnimFrame], tags:&[FrameTag],
+                            options:&Options) -> Result<(), String>
+{
+    let path = path.as_ref();
+    if frames.is_empty() {
+        return Err(String::from("no frames to export"))
+    }
+
+    let ext = options.extrude;
+    let sources : Vec<Rect> = frames
+        .iter()
+        .map(|f| match options.layout {
+            Layout::Grid => Rect { x:0, y:0, w, h },
+            Layout::Tight => trim(&f.pixels, (w, h)),
+        })
+        .collect();
+    let sizes : Vec<Size> = sources.iter().map(|r| Size { w:r.w + ext * 2, h:r.h + ext * 2 }).collect();
+    let (positions, size) = place(&sizes, options);
+
+    // copy each frame with its extrusion, border pixels being repeated outwards
+    let mut pixels = vec![Color::rgba(0, 0, 0, 0); size.w * size.h];
+    for ((frame, src), &(px, py)) in frames.iter().zip(sources.iter()).zip(positions.iter()) {
+        for dy in 0..src.h + ext * 2 {
+            for dx in 0..src.w + ext * 2 {
+                let sx = src.x + dx.saturating_sub(ext).min(src.w - 1);
+                let sy = src.y + dy.saturating_sub(ext).min(src.h - 1);
+                pixels[(py + dy) * size.w + px + dx] = frame.pixels[sy * w + sx];
+            }
+        }
+    }
+
+    let bytes : Vec<u8> = pixels.iter().flat_map(|c| vec![c.r, c.g, c.b, c.a]).collect();
+    image::save_buffer(path, &bytes, size.w as u32, size.h as u32, image::ColorType::Rgba8)
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    let stem = path.file_stem().map_or(String::new(), |s| s.to_string_lossy().into_owned());
+    let sheet = Sheet {
+        frames:frames
+            .iter()
+            .zip(sources.iter().zip(positions.iter()))
+            .enumerate()
+            .map(|(i, (frame, (src, &(x, y))))| (format!("{} {}", stem, i), SheetFrame {
+                frame:Rect { x:x + ext, y:y + ext, w:src.w, h:src.h },
+                rotated:false,
+                trimmed:src.w != w || src.h != h,
+                sprite_source_size:*src,
+                source_size:Size { w, h },
+                duration:frame.delay,
+            }))
+            .collect(),
+        meta:Meta {
+            app:"pixy",
+            version:env!("CARGO_PKG_VERSION"),
+            image:path.file_name().map_or(String::new(), |s| s.to_string_lossy().into_owned()),
+            format:"RGBA8888",
+            size,
+            scale:"1",
+            frame_tags:tags.to_vec(),
+        },
+    };
+
+    let json_path = path.with_extension("json");
+    let json = serde_json::to_string_pretty(&sheet).map_err(|e| e.to_string())?;
+    std::fs::write(&json_path, json).map_err(|e| format!("{}: {}", json_path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sizes(sizes:&[(usize, usize)]) -> Vec<Size> {
+        sizes.iter().map(|&(w, h)| Size { w, h }).collect()
+    }
+
+    #[test]
+    fn grid_layout() {
+        let options = Options { padding:1, ..Options::default() };
+        let (positions, size) = place(&sizes(&[(4, 3); 5]), &options);
+        // as many columns as rows, rounded up
+        assert_eq!(positions, [(1, 1), (6, 1), (11, 1), (1, 5), (6, 5)]);
+        assert_eq!((size.w, size.h), (16, 9));
+
+        let options = Options { columns:Some(2), ..options };
+        let (positions, size) = place(&sizes(&[(4, 3); 5]), &options);
+        assert_eq!(positions, [(1, 1), (6, 1), (1, 5), (6, 5), (1, 9)]);
+        assert_eq!((size.w, size.h), (11, 13));
+    }
+
+    #[test]
+    fn grid_cells_fit_the_largest_frame() {
+        let (positions, size) = place(&sizes(&[(2, 5), (3, 1)]), &Options::default());
+        assert_eq!(positions, [(0, 0), (3, 0)]);
+        assert_eq!((size.w, size.h), (6, 5));
+    }
+
+    #[test]
+    fn tight_layout() {
+        let options = Options { layout:Layout::Tight, ..Options::default() };
+        let (positions, size) = place(&sizes(&[(2, 2), (4, 4), (3, 1), (1, 3)]), &options);
+        // tallest first, on shelves no wider than a square sheet would be
+        assert_eq!(positions, [(0, 4), (0, 0), (2, 4), (4, 0)]);
+        assert_eq!((size.w, size.h), (5, 6));
+
+        // padding counts in the width of the shelves
+        let options = Options { padding:2, ..options };
+        let (positions, size) = place(&sizes(&[(2, 2), (4, 4)]), &options);
+        assert_eq!(positions, [(2, 8), (2, 2)]);
+        assert_eq!((size.w, size.h), (8, 12));
+    }
+
+    #[test]
+    fn trimming() {
+        let (o, x) = (Color::rgba(0, 0, 0, 0), Color::rgb(255, 0, 0));
+        let pixels = [o, o, o, o, x, o, o, o, x];
+        let r = trim(&pixels, (3, 3));
+        assert_eq!((r.x, r.y, r.w, r.h), (1, 1, 2, 2));
+
+        let r = trim(&[o; 4], (2, 2));
+        assert_eq!((r.x, r.y, r.w, r.h), (0, 0, 1, 1));
+    }
+
+    #[test]
+    fn sheet_image_and_description() {
+        let (o, a, b) = (Color::rgba(0, 0, 0, 0), Color::rgb(255, 0, 0), Color::rgb(0, 0, 255));
+        let frames = [
+            AnimFrame { pixels:vec![o, o, o, o, a, b, o, b, a], delay:100 },
+            AnimFrame { pixels:vec![o, o, b, o, o, o, o, o, o], delay:50 },
+        ];
+        let tags = [FrameTag { name:String::from("walk"), from:0, to:1, direction:String::from("pingpong") }];
+        let options = Options { layout:Layout::Tight, padding:1, extrude:1, columns:None };
+
+        let path = std::env::temp_dir().join(format!("pixy-sheet-{}.png", std::process::id()));
+        write(&path, (3, 3), &frames, &tags, &options).unwrap();
+        let image = image::open(&path).unwrap().to_rgba8();
+        let json : serde_json::Value = serde_json::from_str(&std::fs::read_to_string(path.with_extension("json")).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(path.with_extension("json")).unwrap();
+
+        let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+        let first = &json["frames"][format!("{} 0", name)];
+        assert_eq!(first["spriteSourceSize"], serde_json::json!({ "x":1, "y":1, "w":2, "h":2 }));
+        assert_eq!(first["sourceSize"], serde_json::json!({ "w":3, "h":3 }));
+        assert_eq!(first["trimmed"], true);
+        assert_eq!(first["duration"], 100);
+        assert_eq!(json["frames"][format!("{} 1", name)]["spriteSourceSize"], serde_json::json!({ "x":2, "y":0, "w":1, "h":1 }));
+
+        assert_eq!(json["meta"]["frameTags"], serde_json::json!([{ "name":"walk", "from":0, "to":1, "direction":"pingpong" }]));
+        assert_eq!(json["meta"]["size"], serde_json::json!({ "w":image.width(), "h":image.height() }));
+
+        // each frame is copied at its position, surrounded by its extruded border
+        let pixel = |x:u64, y:u64| {
+            let p = image.get_pixel(x as u32, y as u32);
+            Color::rgba(p[0], p[1], p[2], p[3])
+        };
+        for (i, frame) in frames.iter().enumerate() {
+            let entry = &json["frames"][format!("{} {}", name, i)];
+            let (fx, fy) = (entry["frame"]["x"].as_u64().unwrap(), entry["frame"]["y"].as_u64().unwrap());
+            let (sx, sy) = (entry["spriteSourceSize"]["x"].as_u64().unwrap(), entry["spriteSourceSize"]["y"].as_u64().unwrap());
+            let (w, h) = (entry["frame"]["w"].as_u64().unwrap(), entry["frame"]["h"].as_u64().unwrap());
+
+            for y in 0..h {
+                for x in 0..w {
+                    assert_eq!(pixel(fx + x, fy + y), frame.pixels[((sy + y) * 3 + sx + x) as usize]);
+                }
+                assert_eq!(pixel(fx - 1, fy + y), pixel(fx, fy + y));
+                assert_eq!(pixel(fx + w, fy + y), pixel(fx + w - 1, fy + y));
+            }
+        }
+    }
+}
@@ -0,0 +1,357 @@
+use image::{RgbImage, RgbaImage, Rgba};
+use glm::{Mat3, vec3};
+
+use crate::{
+    canvas::{self, Canvas},
+    maths::scale,
+    text,
+    ui::{background, selection},
+};
+
+type Color = [f32; 4];
+
+/// A fragment of a triangle being rasterized: the index of the triangle's first vertex, the
+/// barycentric weights of its three vertices at the pixel center, and how these weights change
+/// from one pixel to the next along x and y.
+struct Fragment {
+    first: usize,
+    w: [f32; 3],
+    dx: [f32; 3],
+    dy: [f32; 3],
+}
+
+impl Fragment {
+    /// Interpolate a vertex attribute, given for the three vertices of the triangle.
+    fn interpolate<const N:usize>(&self, attr:[[f32; N]; 3]) -> [f32; N] {
+        let mut ret = [0.0; N];
+        for (k, v) in ret.iter_mut().enumerate() {
+            *v = (0..3).map(|i| attr[i][k] * self.w[i]).sum();
+        }
+        ret
+    }
+
+    /// Equivalent of GLSL's `fwidth` for an interpolated attribute.
+    fn fwidth<const N:usize>(&self, attr:[[f32; N]; 3]) -> [f32; N] {
+        let mut ret = [0.0; N];
+        for (k, v) in ret.iter_mut().enumerate() {
+            let dx : f32 = (0..3).map(|i| attr[i][k] * self.dx[i]).sum();
+            let dy : f32 = (0..3).map(|i| attr[i][k] * self.dy[i]).sum();
+            *v = dx.abs() + dy.abs();
+        }
+        ret
+    }
+}
+
+/// CPU implementation of the rendering passes. It rasterizes the same vertex buffers as the
+/// OpenGL programs and mimics their shaders, so that frames can be rendered without a GPU (for
+/// screenshots or comparing against reference images).
+pub struct SoftwareRenderer {
+    pub target: RgbaImage,
+}
+
+impl SoftwareRenderer {
+    pub fn new(width:u32, height:u32, clear:Color) -> Self {
+        Self {
+            target: RgbaImage::from_pixel(width, height, to_rgba(clear)),
+        }
+    }
+
+    pub fn into_image(self) -> RgbaImage {
+        self.target
+    }
+
+    /// Same as `background.vert`/`background.frag`: vertices are already in device coordinates.
+    pub fn render_background(&mut self, verts:&[background::Vertex]) {
+        let pos : Vec<[f32; 2]> = verts.iter().map(|v| v.pos.repr).collect();
+        self.fill_triangles(&pos, scale(1.0, 1.0), |f| {
+            let [r, g, b] = f.interpolate(attribute(verts, f.first, |v| v.color.repr));
+            Some([r, g, b, 1.0])
+        });
+    }
+
+    /// Same as `normal.vert`/`normal.frag`, sampling the canvas with nearest filtering.
+    pub fn render_canvas(&mut self, verts:&[canvas::Vertex], view:Mat3, canvas:&Canvas) {
+        let pos : Vec<[f32; 2]> = verts.iter().map(|v| v.pos.repr).collect();
+        let (w, h) = canvas.size();
+        self.fill_triangles(&pos, view, |f| {
+            let [u, v] = f.interpolate(attribute(verts, f.first, |v| v.texPos.repr));
+            let x = ((u * w as f32) as usize).min(w - 1);
+            let y = ((v * h as f32) as usize).min(h - 1);
+            Some(canvas.get_pixel_color(x, y).to_f32())
+        });
+    }
+
+    /// Same as `grid.vert`/`grid.frag`. Colors are given as their uniform values (see
+    /// `grid::uniform_color`).
+    pub fn render_grid(&mut self, verts:&[canvas::Vertex], view:Mat3, canvas_size:(usize, usize),
+                       chunk_size:(usize, usize), grid_color:Color, chunk_color:Color)
+    {
+        let pos : Vec<[f32; 2]> = verts.iter().map(|v| v.pos.repr).collect();
+        let (cw, ch) = (canvas_size.0 as f32, canvas_size.1 as f32);
+        let chunk = [chunk_size.0 as f32, chunk_size.1 as f32];
+
+        self.fill_triangles(&pos, view, |f| {
+            let pix = attribute(verts, f.first, |v| [v.texPos.repr[0] * cw, v.texPos.repr[1] * ch]);
+            let p = f.interpolate(pix);
+            let fw = f.fwidth(pix);
+            let on_line = |step:[f32; 2]| (0..2).any(|k| p[k].rem_euclid(step[k]) < fw[k]);
+
+            if chunk_color[3] > 0.0 && on_line(chunk) {
+                Some(chunk_color)
+            } else if grid_color[3] > 0.0 && on_line([1.0, 1.0]) {
+                Some(grid_color)
+            } else {
+                None
+            }
+        });
+    }
+
+    /// Same as `selection.vert`/`selection.frag`.
+    pub fn render_selection(&mut self, verts:&[selection::Vertex], view:Mat3, atlas:&RgbaImage) {
+        let pos : Vec<[f32; 2]> = verts.iter().map(|v| v.pos.repr).collect();
+        self.fill_triangles(&pos, view, |f| {
+            let [r, g, b] = verts[f.first].onColor.repr;
+            let luminance = (0.3 * r as f32 + 0.59 * g as f32 + 0.11 * b as f32) / 255.0;
+            let tex = f.interpolate(attribute(verts, f.first, |v| v.texPos.repr));
+            let [r, g, b, a] = sample(atlas.width(), atlas.height(), tex, |x, y| {
+                let p = atlas.get_pixel(x, y);
+                [p[0] as f32, p[1] as f32, p[2] as f32, p[3] as f32]
+            });
+            let full = [r / 255.0, g / 255.0, b / 255.0, a / 255.0];
+
+            if luminance < 0.5 { Some(full) } else { Some([0.0, 0.0, 0.0, full[3]]) }
+        });
+    }
+
+    /// Same as `text.vert`/`text.frag`, with the MSDF atlas given by `GlyphAtlas::image`.
+    pub fn render_text(&mut self, verts:&[text::Vertex], view:Mat3, atlas:&RgbImage) {
+        let pos : Vec<[f32; 2]> = verts.iter().map(|v| v.pos.repr).collect();
+        let (aw, ah) = (atlas.width(), atlas.height());
+        self.fill_triangles(&pos, view, |f| {
+            let tex = attribute(verts, f.first, |v| v.texPos.repr);
+            let [r, g, b, _] = sample(aw, ah, f.interpolate(tex), |x, y| {
+                let p = atlas.get_pixel(x, y);
+                [p[0] as f32 / 255.0, p[1] as f32 / 255.0, p[2] as f32 / 255.0, 1.0]
+            });
+            let sig_dist = r.min(g).max(r.max(g).min(b));
+
+            // fwidth(sigDist) can't be derived from the texture, approximate it from the number
+            // of texels covered by the fragment
+            let [fx, fy] = f.fwidth(tex);
+            let w = ((fx * aw as f32).max(fy * ah as f32) * 0.25).max(0.01);
+            let opacity = smoothstep(0.5 - w, 0.5 + w, sig_dist);
+
+            Some([1.0, 1.0, 1.0, opacity])
+        });
+    }
+
+    /// Rasterize a list of triangles (three positions per triangle, transformed by `view` into
+    /// device coordinates), and blend the color returned by `fragment` for each covered pixel the
+    /// same way the OpenGL render state does.
+    fn fill_triangles<F>(&mut self, pos:&[[f32; 2]], view:Mat3, mut fragment:F)
+        where F : FnMut(&Fragment) -> Option<Color>,
+    {
+        let (w, h) = (self.target.width() as f32, self.target.height() as f32);
+        let to_screen = |[x, y]:[f32; 2]| {
+            let ndc = view * vec3(x, y, 1.0);
+            [(ndc.x + 1.0) * 0.5 * w, (1.0 - ndc.y) * 0.5 * h]
+        };
+
+        for (t, tri) in pos.chunks_exact(3).enumerate() {
+            let [p0, p1, p2] = [to_screen(tri[0]), to_screen(tri[1]), to_screen(tri[2])];
+            let area = edge(p0, p1, p2);
+            if area.abs() < std::f32::EPSILON { continue }
+
+            let weights = |p:[f32; 2]| [edge(p1, p2, p) / area, edge(p2, p0, p) / area, edge(p0, p1, p) / area];
+            let origin = weights([0.0, 0.0]);
+            let wx = weights([1.0, 0.0]);
+            let wy = weights([0.0, 1.0]);
+            let dx = [wx[0] - origin[0], wx[1] - origin[1], wx[2] - origin[2]];
+            let dy = [wy[0] - origin[0], wy[1] - origin[1], wy[2] - origin[2]];
+
+            let xs = [p0[0], p1[0], p2[0]];
+            let ys = [p0[1], p1[1], p2[1]];
+            let x1 = xs.iter().cloned().fold(w, f32::min).max(0.0) as u32;
+            let x2 = xs.iter().cloned().fold(0.0, f32::max).min(w) as u32;
+            let y1 = ys.iter().cloned().fold(h, f32::min).max(0.0) as u32;
+            let y2 = ys.iter().cloned().fold(0.0, f32::max).min(h) as u32;
+
+            for y in y1..=y2.min(self.target.height() - 1) {
+                for x in x1..=x2.min(self.target.width() - 1) {
+                    // sample slightly off the pixel center, so that a pixel lying exactly on the
+                    // edge shared by two triangles is only drawn once
+                    let wp = weights([x as f32 + 0.5 + 1.3e-4, y as f32 + 0.5 + 0.7e-4]);
+                    if wp.iter().any(|&c| c < 0.0) { continue }
+
+                    let frag = Fragment { first: t * 3, w: wp, dx, dy };
+                    if let Some(src) = fragment(&frag) {
+                        let dst = self.target.get_pixel_mut(x, y);
+                        *dst = blend(src, *dst);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Gather an attribute of the three vertices of the triangle starting at `first`.
+fn attribute<V, F, const N:usize>(verts:&[V], first:usize, f:F) -> [[f32; N]; 3]
+    where F : Fn(&V) -> [f32; N],
+{
+    [f(&verts[first]), f(&verts[first + 1]), f(&verts[first + 2])]
+}
+
+fn edge(a:[f32; 2], b:[f32; 2], p:[f32; 2]) -> f32 {
+    (b[0] - a[0]) * (p[1] - a[1]) - (b[1] - a[1]) * (p[0] - a[0])
+}
+
+/// Bilinear sampling of a texture with clamp-to-edge wrapping, like the samplers used for the
+/// selection and text atlases.
+fn sample<F>(width:u32, height:u32, [u, v]:[f32; 2], fetch:F) -> Color
+    where F : Fn(u32, u32) -> Color,
+{
+    let (fx, fy) = (u * width as f32 - 0.5, v * height as f32 - 0.5);
+    let (x0, y0) = (fx.floor(), fy.floor());
+    let (tx, ty) = (fx - x0, fy - y0);
+    let clamp = |c:f32, max:u32| (c.max(0.0) as u32).min(max - 1);
+    let (xa, xb) = (clamp(x0, width), clamp(x0 + 1.0, width));
+    let (ya, yb) = (clamp(y0, height), clamp(y0 + 1.0, height));
+
+    let (c00, c10, c01, c11) = (fetch(xa, ya), fetch(xb, ya), fetch(xa, yb), fetch(xb, yb));
+    let mut ret = [0.0; 4];
+    for k in 0..4 {
+        let top = c00[k] + (c10[k] - c00[k]) * tx;
+        let bottom = c01[k] + (c11[k] - c01[k]) * tx;
+        ret[k] = top + (bottom - top) * ty;
+    }
+    ret
+}
+
+fn smoothstep(e0:f32, e1:f32, x:f32) -> f32 {
+    let t = ((x - e0) / (e1 - e0)).max(0.0).min(1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+/// Additive blending of `src * src.alpha + dst * (1 - src.alpha)`, applied to all four channels.
+fn blend(src:Color, dst:Rgba<u8>) -> Rgba<u8> {
+    let a = src[3];
+    let mut ret = [0.0; 4];
+    for k in 0..4 {
+        ret[k] = src[k] * a + (dst[k] as f32 / 255.0) * (1.0 - a);
+    }
+    to_rgba(ret)
+}
+
+fn to_rgba(c:Color) -> Rgba<u8> {
+    let q = |v:f32| (v.max(0.0).min(1.0) * 255.0).round() as u8;
+    Rgba([q(c[0]), q(c[1]), q(c[2]), q(c[3])])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, path::PathBuf};
+
+    use super::*;
+    use crate::{
+        color::Color as Pixel,
+        maths::translate,
+        text::{TextRendererBuilder, HAlign, VAlign},
+    };
+
+    /// Compare a rendered image with its reference in `src/software/golden`. Channels may be off
+    /// by a few units, the rasterization being done with floats. References are written instead
+    /// when `PIXY_BLESS` is set.
+    fn check_golden(name:&str, image:&RgbaImage) {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/software/golden").join(name);
+        if env::var_os("PIXY_BLESS").is_some() {
+            image.save(&path).unwrap();
+            return
+        }
+
+        let reference = image::open(&path)
+            .unwrap_or_else(|e| panic!("{}: {}, run with PIXY_BLESS=1 to create it", path.display(), e))
+            .to_rgba8();
+        assert_eq!(reference.dimensions(), image.dimensions(), "size differs from {}", path.display());
+
+        let differing = reference
+            .pixels()
+            .zip(image.pixels())
+            .filter(|(a, b)| a.0.iter().zip(b.0.iter()).any(|(x, y)| (*x as i32 - *y as i32).abs() > 2))
+            .count();
+        assert_eq!(differing, 0, "{} pixels differ from {}", differing, path.display());
+    }
+
+    /// A 4x4 canvas with opaque, translucent and transparent pixels.
+    fn canvas() -> Canvas {
+        let mut canvas = Canvas::new(4, 4);
+        for y in 0..4 {
+            for x in 0..4 {
+                let c = match (x + y) % 4 {
+                    0 => Pixel::rgba(255, 0, 0, 255),
+                    1 => Pixel::rgba(0, 255, 0, 128),
+                    2 => Pixel::rgba(0, 0, 255, 255),
+                    _ => Pixel::rgba(0, 0, 0, 0),
+                };
+                canvas.set_pixel_color(x, y, c);
+            }
+        }
+        canvas
+    }
+
+    /// Canvas vertices and a view showing each canvas pixel as an 8x8 square filling a 32x32
+    /// target.
+    fn canvas_quad() -> (Vec<canvas::Vertex>, Mat3) {
+        let vert = |x:f32, y:f32| canvas::Vertex {
+            pos:canvas::VertexPosition::new([x * 4.0, y * 4.0]),
+            texPos:canvas::TexPosition::new([x, y]),
+        };
+        let verts = vec![vert(0.0, 0.0), vert(1.0, 0.0), vert(1.0, 1.0), vert(1.0, 1.0), vert(0.0, 1.0), vert(0.0, 0.0)];
+
+        (verts, scale(0.5, -0.5) * translate(-2.0, -2.0))
+    }
+
+    #[test]
+    fn background() {
+        let mut renderer = SoftwareRenderer::new(64, 48, [0.3, 0.3, 0.3, 1.0]);
+        renderer.render_background(&background::render_background((64.0, 48.0)));
+
+        check_golden("background.png", &renderer.into_image());
+    }
+
+    #[test]
+    fn canvas_over_background() {
+        let (verts, view) = canvas_quad();
+        let mut renderer = SoftwareRenderer::new(32, 32, [0.3, 0.3, 0.3, 1.0]);
+        renderer.render_background(&background::render_background((32.0, 32.0)));
+        renderer.render_canvas(&verts, view, &canvas());
+
+        check_golden("canvas.png", &renderer.into_image());
+    }
+
+    #[test]
+    fn grid() {
+        let (verts, view) = canvas_quad();
+        let mut renderer = SoftwareRenderer::new(32, 32, [0.3, 0.3, 0.3, 1.0]);
+        renderer.render_canvas(&verts, view, &canvas());
+        renderer.render_grid(&verts, view, (4, 4), (2, 2), [0.0, 0.0, 0.0, 0.5], [1.0, 1.0, 1.0, 1.0]);
+
+        check_golden("grid.png", &renderer.into_image());
+    }
+
+    #[test]
+    fn text() {
+        // the atlas is built without a graphics context, from a pregenerated atlas of the UI font,
+        // so that the reference doesn't depend on the version of msdfgen
+        let mut builder = TextRendererBuilder::for_resolution(16).without_cache();
+        let font = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/software/fixtures/jackinput-16.json");
+        let fid = builder.add_font_from_json(font).unwrap();
+        let atlas = builder.build_atlas().unwrap();
+
+        let (w, h) = (160.0, 48.0);
+        let verts = atlas.render_text("Pixy 0.1", (HAlign::Left(4), VAlign::Top(4)), (w, h), fid, 24.0);
+        let mut renderer = SoftwareRenderer::new(w as u32, h as u32, [0.3, 0.3, 0.3, 1.0]);
+        renderer.render_text(&verts, scale(2.0 / w, -2.0 / h) * translate(-w * 0.5, -h * 0.5), &atlas.image());
+
+        check_golden("text.png", &renderer.into_image());
+    }
+}
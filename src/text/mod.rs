@@ -4,14 +4,15 @@ use serde::*;
 use serde_json;
 
 use std::fs::File;
-use std::{cell::Cell, path::Path, fs, collections::{HashMap, BTreeMap}};
+use std::{cell::Cell, path::{Path, PathBuf}, fs, collections::{HashMap, HashSet, BTreeMap}};
 use rusttype::{Font, Point, Scale};
+use image::{RgbImage, Rgb, GenericImageView};
 
 use ttf_parser::{Font as TTFFont};
 use msdfgen::{FontExt, Bitmap, RGB, Range, EDGE_THRESHOLD, OVERLAP_SUPPORT};
 
 use luminance::{
-    texture::{Texture, GenMipmaps, Sampler, Dim2},
+    texture::{Texture, TextureError, GenMipmaps, Sampler, Dim2},
     pixel::{NormR8UI, NormRGB8UI},
     context::GraphicsContext,
 };
@@ -26,6 +27,7 @@ struct GlyphBounds {
     right:f32,
 }
 
+/// Position and size of a glyph in the atlas, in texels.
 #[derive(Clone, Copy, Serialize, Deserialize)]
 pub struct GlyphRect {
     pub atlas_coord: (f32, f32),
@@ -40,27 +42,121 @@ impl From<FontID> for usize {
     fn from(fid:FontID) -> usize { fid.0 }
 }
 
-#[derive(Serialize, Deserialize)]
+/// Metrics and glyphs of a font. Horizontal metrics are in ems.
+#[derive(Clone, Serialize, Deserialize)]
 pub struct FontInfo {
     toppest: f32,
     lowest: f32,
-    glyphs: BTreeMap<u8, GlyphRect>,
+    glyphs: BTreeMap<char, GlyphRect>,
+    #[serde(default)]
+    advances: BTreeMap<char, f32>,
+    // kerning between two loaded characters, by left then right character, when it is not zero
+    #[serde(default)]
+    kerning: BTreeMap<char, BTreeMap<char, f32>>,
 }
 
-pub struct TextRenderer {
-    pub atlas: Texture<Dim2, NormRGB8UI>,
+impl FontInfo {
+    /// Horizontal advance of a character, or half an em if it isn't loaded.
+    fn advance(&self, c:char) -> f32 {
+        self.advances.get(&c).cloned().unwrap_or(0.5)
+    }
+
+    fn kerning(&self, left:char, right:char) -> f32 {
+        self.kerning.get(&left).and_then(|k| k.get(&right)).cloned().unwrap_or(0.0)
+    }
+
+    /// Width of a line of text, in ems.
+    fn line_width(&self, line:&str) -> f32 {
+        let mut prev = None;
+        line.chars()
+            .map(|c| {
+                let kern = prev.map_or(0.0, |p| self.kerning(p, c));
+                prev = Some(c);
+                kern + self.advance(c)
+            })
+            .sum()
+    }
+
+    /// Split a line into lines no wider than `max_width` ems, breaking between words when
+    /// possible.
+    fn wrap(&self, line:&str, max_width:f32) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut current = String::new();
+
+        for word in line.split(' ') {
+            let candidate = if current.is_empty() { word.to_string() } else { format!("{} {}", current, word) };
+            if self.line_width(&candidate) <= max_width {
+                current = candidate;
+                continue
+            }
+
+            if !current.is_empty() {
+                lines.push(std::mem::replace(&mut current, String::new()));
+            }
+
+            // words wider than a line are broken anywhere
+            for c in word.chars() {
+                current.push(c);
+                if self.line_width(&current) > max_width && current.chars().count() > 1 {
+                    current.pop();
+                    lines.push(std::mem::replace(&mut current, c.to_string()));
+                }
+            }
+        }
+
+        lines.push(current);
+        lines
+    }
+}
+
+/// Side of the square pages the glyph atlas is made of. The atlas is one page wide, and grows by
+/// one page when it is full.
+const PAGE_SIZE : u32 = 512;
+
+/// Font data used to rasterize glyphs when they are first needed.
+struct GlyphSource {
+    ttf: Vec<u8>,
+    // where the glyphs rasterized so far are saved
+    cache: Option<PathBuf>,
+    // number of characters loaded when the cache was last written
+    saved: usize,
+}
+
+/// Glyphs of the fonts, rasterized to an atlas kept in memory, and the layout of text with them.
+/// It doesn't need a graphics context, `TextRenderer` keeps a texture up to date with it.
+pub struct GlyphAtlas {
     pub fonts: BTreeMap<FontID, FontInfo>,
     pub resolution: f32,
 
+    sources: BTreeMap<FontID, GlyphSource>,
+    // RGB texels of the atlas, row by row
+    texels: Vec<u8>,
+    atlas_size: (u32, u32),
+    // number of glyph cells used in the atlas
+    cells: u32,
+    // characters the fonts have no glyph for
+    missing: HashSet<(FontID, char)>,
+    // first and past the last rows changed since the texture was last updated
+    dirty: Option<(u32, u32)>,
+}
+
+pub struct TextRenderer {
+    pub atlas: Texture<Dim2, NormRGB8UI>,
+    pub glyphs: GlyphAtlas,
+
     text_cache: Cell<HashMap<(String, FontID), Vec<Vertex>>>,
+
+    sampler: Sampler,
 }
 
+#[derive(Clone, Copy)]
 pub enum HAlign {
     Left(usize),
     Center,
     Right(usize),
 }
 
+#[derive(Clone, Copy)]
 pub enum VAlign {
     Top(usize),
     Center,
@@ -69,89 +165,334 @@ pub enum VAlign {
 
 type Alignment = (HAlign, VAlign);
 
-impl TextRenderer {
+impl GlyphAtlas {
+    /// Lay out a text, which may span several lines. Lines are also wrapped to fit in the screen
+    /// width, and stack downwards from the top or upwards from the bottom depending on `va`.
     pub fn render_text<S:AsRef<str>>(&self, text:S, (ha, va):Alignment, (screenw, screenh):(f32, f32), id:FontID, size:f32)
         -> Vec<Vertex>
     {
-        let scale = size / self.resolution;
         let font = self.fonts.get(&id).unwrap();
-        let text : Vec<Option<GlyphRect>> = text.as_ref().chars().map(|c| font.glyphs.get(&(c as u8)).cloned()).collect();
 
-        let mut text_width = 0.0;
-
-        for c in &text {
-            if let Some(rect) = c {
-                text_width += size * (rect.bounds.right - rect.bounds.left);
-            } else {
-                text_width += self.resolution * 0.5 * scale;
-            }
-        }
+        let max_width = match ha {
+            HAlign::Left(offset) | HAlign::Right(offset) => screenw - offset as f32,
+            HAlign::Center => screenw,
+        };
+        let lines : Vec<String> = text.as_ref()
+            .lines()
+            .flat_map(|l| font.wrap(l, max_width / size))
+            .collect();
+        let n = lines.len() as f32;
 
-        let toppest = font.toppest * size;
         let bottomest = font.lowest * size;
 
+        // TODO: correct the trick used to show accents which are outside font's bounds
+        // baseline of the first line
+        let sy = match va {
+            VAlign::Top(offset) => offset as f32 + size - bottomest,//+ toppest - bottomest,
+            VAlign::Center => (screenh - size * n) * 0.5,
+            VAlign::Bottom(offset) => screenh - offset as f32 + bottomest - size * (n - 1.0),
+        };
+
+        lines
+            .iter()
+            .enumerate()
+            .flat_map(|(i, line)| self.render_line(font, line, ha, sy + size * i as f32, screenw, size))
+            .collect()
+    }
+
+    pub fn text_width(&self, text:&str, id:FontID, size:f32) -> f32 {
+        self.fonts.get(&id).map_or(0.0, |font| size * font.line_width(text))
+    }
+
+    fn render_line(&self, font:&FontInfo, line:&str, ha:HAlign, sy:f32, screenw:f32, size:f32) -> Vec<Vertex> {
+        let (aw, ah) = (self.atlas_size.0 as f32, self.atlas_size.1 as f32);
+        let text_width = size * font.line_width(line);
+
         let mut sx = match ha {
             HAlign::Left(offset) => offset as f32,
             HAlign::Center => (screenw - text_width) * 0.5,
             HAlign::Right(offset) => screenw - offset as f32 - text_width,
         };
 
-        // TODO: correct the trick used to show accents which are outside font's bounds
-        let sy = match va {
-            VAlign::Top(offset) => offset as f32 + size - bottomest,//+ toppest - bottomest,
-            VAlign::Center => (screenh - size) * 0.5,
-            VAlign::Bottom(offset) => screenh - offset as f32 + bottomest,
+        let mut prev = None;
+        let mut ret = Vec::new();
+
+        for c in line.chars() {
+            if let Some(p) = prev {
+                sx += size * font.kerning(p, c);
+            }
+            prev = Some(c);
+
+            if let Some(rect) = font.glyphs.get(&c) {
+                let (x,y) = (rect.atlas_coord.0 / aw, rect.atlas_coord.1 / ah); // topleft coords in atlas
+                let (w,h) = (rect.atlas_size.0 / aw, rect.atlas_size.1 / ah);   // rect size of glyph in atlas
+                let (top, left, bottom, right) = (
+                    size * rect.bounds.top,
+                    size * rect.bounds.left,
+                    size * rect.bounds.bottom,
+                    size * rect.bounds.right,
+                );
+                ret.extend_from_slice(&[
+                    Vertex {
+                        pos: VP::new([sx + left, sy + top]),
+                        texPos: TP::new([x, y]),
+                    },
+                    Vertex {
+                        pos: VP::new([sx + left, sy + bottom]),
+                        texPos: TP::new([x, y+h]),
+                    },
+                    Vertex {
+                        pos: VP::new([sx + right, sy + bottom]),
+                        texPos: TP::new([x+w, y+h]),
+                    },
+                    Vertex {
+                        pos: VP::new([sx + right, sy + bottom]),
+                        texPos: TP::new([x+w, y+h]),
+                    },
+                    Vertex {
+                        pos: VP::new([sx + right, sy + top]),
+                        texPos: TP::new([x+w, y]),
+                    },
+                    Vertex {
+                        pos: VP::new([sx + left, sy + top]),
+                        texPos: TP::new([x, y]),
+                    }
+                ]);
+            }
+
+            sx += size * font.advance(c);
+        }
+
+        ret
+    }
+
+    /// Load the metrics and rasterize the glyphs of the characters of `text` which are not known
+    /// yet, so that `render_text` can display it.
+    pub fn load_glyphs(&mut self, text:&str, id:FontID) {
+        for c in text.chars() {
+            let known = self.fonts.get(&id).map_or(true, |font| font.advances.contains_key(&c));
+            if known || self.missing.contains(&(id, c)) {
+                continue
+            }
+
+            if self.load_glyph(id, c).is_none() {
+                self.missing.insert((id, c));
+            }
+        }
+    }
+
+    fn load_glyph(&mut self, id:FontID, c:char) -> Option<()> {
+        let res = self.resolution as u32;
+        let source = self.sources.get(&id)?;
+        let font = self.fonts.get(&id)?;
+
+        let f = TTFFont::from_data(&source.ttf, 0)?;
+        let glyph = f.glyph_index(c)?;
+        let upem = f.units_per_em()? as f32;
+        let advance = f.glyph_hor_advance(glyph).map_or(0.5, |a| a as f32 / upem);
+
+        // kerning pairs between this character and the ones already loaded
+        let mut kerning = Vec::new();
+        for other in font.advances.keys().cloned().chain(Some(c)) {
+            if let Some(other_glyph) = f.glyph_index(other) {
+                if let Some(k) = f.glyphs_kerning(other_glyph, glyph).filter(|k| *k != 0) {
+                    kerning.push((other, c, k as f32 / upem));
+                }
+                if let Some(k) = f.glyphs_kerning(glyph, other_glyph).filter(|k| *k != 0) {
+                    kerning.push((c, other, k as f32 / upem));
+                }
+            }
+        }
+
+        // characters such as spaces have metrics but no outline
+        let raster = f.glyph_shape(glyph).and_then(|shape| rasterize_glyph(shape, res, upem as f64));
+
+        if let Some((bitmap, (top, left, bottom, right), bounds)) = raster {
+            let (gx, gy) = self.alloc_cell();
+            self.upload(gx, gy, res, res, bitmap.raw_pixels());
+
+            self.fonts.get_mut(&id)?.glyphs.insert(c, GlyphRect {
+                atlas_coord: (gx as f32 + left as f32, gy as f32 + top as f32),
+                atlas_size: ((right - left) as f32, (bottom - top) as f32),
+                bounds,
+            });
+        }
+
+        let font = self.fonts.get_mut(&id)?;
+        font.advances.insert(c, advance);
+        for (l, r, k) in kerning {
+            font.kerning.entry(l).or_insert_with(BTreeMap::new).insert(r, k);
+        }
+
+        Some(())
+    }
+
+    /// Find room for a new glyph in the atlas, adding a page to it if it is full, and return the
+    /// topleft corner of the glyph's cell.
+    fn alloc_cell(&mut self) -> (u32, u32) {
+        let res = self.resolution as u32;
+        let per_row = self.atlas_size.0 / res;
+        let (x, y) = (self.cells % per_row, self.cells / per_row);
+
+        if (y + 1) * res > self.atlas_size.1 {
+            self.add_page();
+        }
+
+        self.cells += 1;
+        (x * res, y * res)
+    }
+
+    /// Grow the atlas by one page.
+    fn add_page(&mut self) {
+        let (aw, ah) = self.atlas_size;
+        self.texels.resize((aw * (ah + PAGE_SIZE) * 3) as usize, 0);
+        self.atlas_size = (aw, ah + PAGE_SIZE);
+    }
+
+    /// Write a rectangle of RGB texels to the atlas.
+    fn upload(&mut self, x:u32, y:u32, w:u32, h:u32, data:&[u8]) {
+        let aw = self.atlas_size.0;
+        for (row, line) in data.chunks((w * 3) as usize).enumerate() {
+            let start = (((y + row as u32) * aw + x) * 3) as usize;
+            self.texels[start..start + line.len()].copy_from_slice(line);
+        }
+
+        self.dirty = Some(match self.dirty {
+            Some((y1, y2)) => (y1.min(y), y2.max(y + h)),
+            None => (y, y + h),
+        });
+    }
+
+    /// Copy of the atlas, as sampled by the text shader.
+    pub fn image(&self) -> RgbImage {
+        let (aw, ah) = self.atlas_size;
+        RgbImage::from_raw(aw, ah, self.texels.clone()).expect("Font atlas has an unexpected size")
+    }
+
+    /// Write the glyphs of the fonts loaded from TTF files to the cache, so that they don't need
+    /// to be rasterized again on the next run. Fonts without new glyphs are skipped.
+    pub fn save_cache(&mut self) -> Result<(), String> {
+        let res = self.resolution as u32;
+        let per_row = PAGE_SIZE / res;
+
+        for (id, source) in self.sources.iter_mut() {
+            let (path, font) = match (&source.cache, self.fonts.get(id)) {
+                (Some(path), Some(font)) if font.advances.len() != source.saved => (path.clone(), font),
+                _ => continue,
+            };
+
+            // copy the cells used by the font to a new image, one cell per glyph
+            let mut font = font.clone();
+            let rows = (font.glyphs.len() as u32 + per_row - 1) / per_row;
+            let mut image = RgbImage::new(PAGE_SIZE, rows.max(1) * res);
+            let aw = self.atlas_size.0;
+
+            for (i, rect) in font.glyphs.values_mut().enumerate() {
+                let (cx, cy) = ((rect.atlas_coord.0 as u32 / res) * res, (rect.atlas_coord.1 as u32 / res) * res);
+                let (nx, ny) = ((i as u32 % per_row) * res, (i as u32 / per_row) * res);
+
+                for y in 0..res {
+                    for x in 0..res {
+                        let t = (((cy + y) * aw + cx + x) * 3) as usize;
+                        image.put_pixel(nx + x, ny + y, Rgb([self.texels[t], self.texels[t+1], self.texels[t+2]]));
+                    }
+                }
+
+                rect.atlas_coord.0 += nx as f32 - cx as f32;
+                rect.atlas_coord.1 += ny as f32 - cy as f32;
+            }
+
+            let image_path = path.with_extension("png");
+            let saved = font.advances.len();
+            let info = MSDFObjectInfo {
+                image_file: image_path.file_name().unwrap().to_string_lossy().into_owned(),
+                resolution: res,
+                font,
+            };
+
+            let written = image.save(&image_path)
+                .map_err(|e| format!("{}: {}", image_path.display(), e))
+                .and_then(|_| File::create(&path).map_err(|e| format!("{}: {}", path.display(), e)))
+                .and_then(|file| serde_json::to_writer(file, &info).map_err(|e| format!("{}: {}", path.display(), e)));
+
+            // a cache which can't be written isn't tried again, so that the error is reported once
+            if let Err(e) = written {
+                source.cache = None;
+                return Err(e)
+            }
+            source.saved = saved;
+        }
+
+        Ok(())
+    }
+
+    /// Copy the glyphs of a pregenerated atlas to the atlas, and register the font.
+    fn add_msdf_font(&mut self, id:FontID, image:&RgbImage, mut font:FontInfo) {
+        let res = self.resolution as u32;
+        let mut moved = HashMap::new();
+
+        for rect in font.glyphs.values_mut() {
+            let (cx, cy) = ((rect.atlas_coord.0 as u32 / res) * res, (rect.atlas_coord.1 as u32 / res) * res);
+
+            let (nx, ny) = match moved.get(&(cx, cy)) {
+                Some(&cell) => cell,
+                None => {
+                    let cell = self.alloc_cell();
+                    let texels : Vec<u8> = image.view(cx, cy, res, res)
+                        .pixels()
+                        .flat_map(|(_, _, p)| p.0.to_vec())
+                        .collect();
+                    self.upload(cell.0, cell.1, res, res, &texels);
+                    moved.insert((cx, cy), cell);
+                    cell
+                },
+            };
+
+            rect.atlas_coord.0 += nx as f32 - cx as f32;
+            rect.atlas_coord.1 += ny as f32 - cy as f32;
+        }
+
+        self.fonts.insert(id, font);
+    }
+}
+
+impl TextRenderer {
+    pub fn render_text<S:AsRef<str>>(&self, text:S, align:Alignment, screen:(f32, f32), id:FontID, size:f32)
+        -> Vec<Vertex>
+    {
+        self.glyphs.render_text(text, align, screen, id, size)
+    }
+
+    /// Same as `GlyphAtlas::load_glyphs`, updating the texture with the new glyphs. The glyphs
+    /// which failed to upload are not retried, they are only drawn once the atlas is updated again.
+    pub fn load_glyphs<C:GraphicsContext>(&mut self, ctx:&mut C, text:&str, id:FontID) -> Result<(), TextureError> {
+        self.glyphs.load_glyphs(text, id);
+        self.update_texture(ctx)
+    }
+
+    /// Upload the rows of the atlas changed since the last update, or the whole atlas to a new
+    /// texture when it has grown.
+    fn update_texture<C:GraphicsContext>(&mut self, ctx:&mut C) -> Result<(), TextureError> {
+        let (aw, ah) = self.glyphs.atlas_size;
+        let (y1, y2) = match self.glyphs.dirty.take() {
+            Some(rows) => rows,
+            None => return Ok(()),
         };
 
+        if self.atlas.size() != [aw, ah] {
+            let atlas : Texture<Dim2, NormRGB8UI> = Texture::new(ctx, [aw, ah], 0, self.sampler)?;
+            atlas.upload_raw(GenMipmaps::No, &self.glyphs.texels)?;
+            self.atlas = atlas;
+        } else {
+            let rows = &self.glyphs.texels[(y1 * aw * 3) as usize..(y2 * aw * 3) as usize];
+            self.atlas.upload_part_raw(GenMipmaps::No, [0, y1], [aw, y2 - y1], rows)?;
+        }
 
-        text
-            .into_iter()
-            .map(|rect| {
-                rect.map(|rect| {
-                    let (x,y) = rect.atlas_coord; // topleft coords in atlas
-                    let (w,h) = rect.atlas_size;  // rect size of glyph in atlas
-                    let (top, left, bottom, right) = (
-                        size * rect.bounds.top,
-                        size * rect.bounds.left,
-                        size * rect.bounds.bottom,
-                        size * rect.bounds.right,
-                    );
-                    let ret = vec![
-                        Vertex {
-                            pos: VP::new([sx + left, sy + top]),
-                            texPos: TP::new([x, y]),
-                        },
-                        Vertex {
-                            pos: VP::new([sx + left, sy + bottom]),
-                            texPos: TP::new([x, y+h]),
-                        },
-                        Vertex {
-                            pos: VP::new([sx + right, sy + bottom]),
-                            texPos: TP::new([x+w, y+h]),
-                        },
-                        Vertex {
-                            pos: VP::new([sx + right, sy + bottom]),
-                            texPos: TP::new([x+w, y+h]),
-                        },
-                        Vertex {
-                            pos: VP::new([sx + right, sy + top]),
-                            texPos: TP::new([x+w, y]),
-                        },
-                        Vertex {
-                            pos: VP::new([sx + left, sy + top]),
-                            texPos: TP::new([x, y]),
-                        }
-                    ];
-                    sx += right - left;
-                    ret
-                })
-                .unwrap_or_else(|| {
-                    sx += self.resolution * 0.5 * scale;
-                    vec![]
-                })
-            })
-            .flatten()
-            .collect()
+        Ok(())
+    }
+
+    pub fn save_cache(&mut self) -> Result<(), String> {
+        self.glyphs.save_cache()
     }
 
     pub fn render_text_cached<'a, S:AsRef<str>>(& 'a self, text:S, pos:Alignment, id:FontID) -> & 'a [Vertex] {
@@ -163,157 +504,203 @@ impl TextRenderer {
     }
 }
 
+/// Generate the MSDF bitmap of a glyph, with the rectangle it occupies in the bitmap (top, left,
+/// bottom, right, in texels) and its bounds in ems.
+fn rasterize_glyph(mut shape:msdfgen::Shape, res:u32, upem:f64) -> Option<(Bitmap<RGB<u8>>, (f64, f64, f64, f64), GlyphBounds)> {
+    let mut map = Bitmap::new(res, res);
+    let mut bounds = shape.get_bounds();
+    let framing = bounds.autoframe(res, res, Range::Px(2.0 * (res / 16) as f64), None)?;
+
+    shape.edge_coloring_simple(3.0, 0);
+    shape.generate_msdf(&mut map, &framing, EDGE_THRESHOLD, OVERLAP_SUPPORT);
+
+    std::mem::swap(&mut bounds.bottom, &mut bounds.top);
+
+    map.flip_y();
+    let mapu8 : Bitmap<RGB<u8>> = map.convert();
+
+    let rect = (
+        (bounds.top + framing.translate.y) * framing.scale.y,
+        (bounds.left + framing.translate.x) * framing.scale.x,
+        (bounds.bottom + framing.translate.y) * framing.scale.y,
+        (bounds.right + framing.translate.x) * framing.scale.x,
+    );
+
+    let bounds = GlyphBounds {
+        bottom: (-bounds.top / upem) as f32,
+        top: (-bounds.bottom / upem) as f32,
+        left: (bounds.left / upem) as f32,
+        right: (bounds.right / upem) as f32,
+    };
+
+    Some((mapu8, rect, bounds))
+}
+
 enum FontObject {
     TTF(Vec<u8>),
-    MSDF(Vec<u8>, HashMap<u8, GlyphRect>),
+    // atlas generated beforehand, and the font it was generated from when it is known
+    MSDF(RgbImage, FontInfo, Option<Vec<u8>>),
 }
 
+/// Description of a pregenerated atlas. The image is divided in square cells of `resolution`
+/// texels, with at most one glyph per cell.
 #[derive(Serialize, Deserialize)]
 struct MSDFObjectInfo {
+    // relative to the description file
     image_file:String,
-    glyphs:HashMap<u8, GlyphRect>,
+    resolution:u32,
+    font:FontInfo,
+}
+
+impl MSDFObjectInfo {
+    fn load(file:&Path) -> Option<(RgbImage, FontInfo, u32)> {
+        let info : MSDFObjectInfo = serde_json::from_reader(File::open(file).ok()?).ok()?;
+        let image_file = file.parent()?.join(&info.image_file);
+        let image = image::open(image_file).ok()?.to_rgb();
+
+        Some((image, info.font, info.resolution))
+    }
+}
+
+/// Cache file of the glyphs rasterized from a font at a given resolution.
+fn cache_path(ttf:&[u8], resolution:u32) -> Option<PathBuf> {
+    // FNV-1a, which unlike the std hasher is stable between runs and compiler versions
+    let hash = ttf.iter().fold(0xcbf29ce484222325u64, |h, b| (h ^ *b as u64).wrapping_mul(0x100000001b3));
+
+    Some(crate::assets::cache_dir()?.join(format!("font-{:016x}-{}.json", hash, resolution)))
 }
 
 pub struct TextRendererBuilder {
     fonts: Vec<FontObject>,
     resolution: u32,
+    // whether rasterized glyphs are read from and written to the cache
+    cache: bool,
 }
 
 impl TextRendererBuilder {
     pub fn for_resolution(resolution:u32) -> Self {
         Self {
             fonts: Vec::new(),
-            resolution
+            resolution,
+            cache: true,
         }
     }
 
+    /// Rasterize all the glyphs of the fonts added afterwards, and never write them to the cache,
+    /// so that the atlas doesn't depend on previous runs.
+    pub fn without_cache(mut self) -> Self {
+        self.cache = false;
+        self
+    }
+
+    fn cache_path(&self, ttf:&[u8]) -> Option<PathBuf> {
+        if self.cache { cache_path(ttf, self.resolution) } else { None }
+    }
+
     pub fn add_font_from_ttf<P:AsRef<Path>>(&mut self, file:P) -> Option<FontID> {
-        let ret = FontID(self.fonts.len());
         let content : Vec<u8> = fs::read(file).ok()?;
 
-        self.fonts.push(FontObject::TTF(content));
-
-        Some(ret)
+        Some(self.add_font_from_ttf_data(content))
     }
 
-    pub fn add_font_from_json<P:AsRef<Path>>(&mut self, file:P) -> Option<FontID> {
+    /// Same as `add_font_from_ttf`, with the content of the font file already loaded. The glyphs
+    /// rasterized on a previous run are reused when they are in the cache.
+    pub fn add_font_from_ttf_data(&mut self, content:Vec<u8>) -> FontID {
         let ret = FontID(self.fonts.len());
-        let file = fs::File::open(file).ok()?;
-        let msdf_info : MSDFObjectInfo = serde_json::from_reader(file).ok()?;
 
-        let img = image::load_from_memory(&fs::read(msdf_info.image_file).ok()?).ok()?.to_rgb().into_raw();
-        
-        self.fonts.push(FontObject::MSDF(img, msdf_info.glyphs));
-        Some(ret)
-    }
+        let cached = self.cache_path(&content)
+            .and_then(|path| MSDFObjectInfo::load(&path))
+            .filter(|(_, _, res)| *res == self.resolution);
 
-    pub fn build<C:GraphicsContext>(&self, ctx: &mut C, sampler: Sampler) -> Option<TextRenderer> {
-        let chars : Vec<(usize, char)> = (33..255u8).map(|n| n as char).enumerate().collect();
+        self.fonts.push(match cached {
+            Some((image, font, _)) => FontObject::MSDF(image, font, Some(content)),
+            None => FontObject::TTF(content),
+        });
 
-        let res = self.resolution;
-        let nb_chars = chars.len() as u32;
-        let mut min_s = 512;
-        while (min_s * min_s) / (res * res) < nb_chars {
-            min_s *= 2
-        }
+        ret
+    }
 
+    /// Add a font from a pregenerated atlas, as written by `TextRenderer::save_cache`. Fails if the
+    /// atlas was not generated for the resolution of the builder.
+    pub fn add_font_from_json<P:AsRef<Path>>(&mut self, file:P) -> Option<FontID> {
+        let ret = FontID(self.fonts.len());
+        let (image, font, res) = MSDFObjectInfo::load(file.as_ref())?;
 
-        let nf = self.fonts.len() as u32;
-        let (aw, ah) = (min_s, min_s * nf);
-        let atlas : Texture<Dim2, NormRGB8UI> = Texture::new(ctx
-                                                             , [aw, ah]
-                                                             , 0, sampler).ok()?;
-        let mut fonts = BTreeMap::new();
+        if res != self.resolution {
+            return None
+        }
 
-        let glyphs_per_row = min_s / res;
+        self.fonts.push(FontObject::MSDF(image, font, None));
+        Some(ret)
+    }
 
-        //let mut map : Vec<u8> = Vec::with_capacity((res * res) as usize);
-        let mut map = Bitmap::new(res, res);
+    /// Create the atlas in memory, with the glyphs of printable ASCII characters, and write new
+    /// glyphs to the cache.
+    pub fn build_atlas(&self) -> Option<GlyphAtlas> {
+        // Printable ASCII is loaded right away, other glyphs when they are first displayed
+        let preloaded : String = (32..127u8).map(|n| n as char).collect();
+
+        let mut atlas = GlyphAtlas {
+            fonts: BTreeMap::new(),
+            resolution: self.resolution as f32,
+            sources: BTreeMap::new(),
+            texels: vec![0; (PAGE_SIZE * PAGE_SIZE * 3) as usize],
+            atlas_size: (PAGE_SIZE, PAGE_SIZE),
+            cells: 0,
+            missing: HashSet::new(),
+            dirty: None,
+        };
 
         for (fi, content) in self.fonts.iter().enumerate() {
-            let fy = min_s * fi as u32;
+            let id = FontID(fi);
             match content {
             FontObject::TTF(content) => {
-                let mut original_size = None;
-
                 let f = TTFFont::from_data(&content, 0)?;
                 let toppest = f.ascender() as f32 / f.height() as f32;
                 let lowest  = f.descender() as f32 / f.height() as f32;
-                let mut glyphs = BTreeMap::new();
-
-                for (ci, c) in &chars {
-                    let x = ci % glyphs_per_row as usize;
-                    let y = ci / glyphs_per_row as usize;
-
-                    let (w, h) = (res, res);
-                    let glyph = match f.glyph_index(*c) {
-                        Some(id) => id,
-                        None => continue,
-                    };
-                    let mut shape = match f.glyph_shape(glyph) {
-                        Some(shape) => shape,
-                        None => continue,
-                    };
-
-                    let mut bounds = shape.get_bounds();
-                    let framing = bounds.autoframe(res, res, Range::Px(2.0 * (res / 16) as f64), None).unwrap();
-
-                    let origin = *original_size.get_or_insert(framing.range);
-
-                    shape.edge_coloring_simple(3.0, 0);
-                    shape.generate_msdf(&mut map, &framing, EDGE_THRESHOLD, OVERLAP_SUPPORT);
-
-                    std::mem::swap(&mut bounds.bottom, &mut bounds.top);
-
-                    map.flip_y();
-                    let mapu8 : Bitmap<RGB<u8>> = map.convert();
-
-                    let (top, left, bottom, right) = (
-                        (bounds.top + framing.translate.y) * framing.scale.y,
-                        (bounds.left + framing.translate.x) * framing.scale.x,
-                        (bounds.bottom + framing.translate.y) * framing.scale.y,
-                        (bounds.right + framing.translate.x) * framing.scale.x,
-                    );
-
-                    let (gx, gy) = (res * x as u32, fy + res * y as u32);
-                    atlas.upload_part_raw(GenMipmaps::No
-                       , [gx, gy]
-                       , [w, h], mapu8.raw_pixels()).ok().unwrap();
-
-                    let scale = framing.range / origin;
-                    let bounds = GlyphBounds {
-                        bottom: (-bounds.top / res as f64 * framing.scale.y * scale) as f32,
-                        top: (-bounds.bottom / res as f64 * framing.scale.y * scale) as f32,
-                        left: (bounds.left / res as f64 * framing.scale.x * scale) as f32,
-                        right: (bounds.right / res as f64 * framing.scale.x * scale) as f32,
-                    };
-
-                    glyphs.insert(*c as u8, GlyphRect {
-                        atlas_coord: ((gx as f32 + left as f32) / aw as f32, (gy as f32 + top as f32) / ah as f32),
-                        atlas_size: ((right - left) as f32 / aw as f32, (bottom - top) as f32 / ah as f32),
-                        bounds,
-                    });
-                }
 
-                fonts.insert(FontID(fi), FontInfo { toppest, lowest, glyphs });
+                atlas.fonts.insert(id, FontInfo {
+                    toppest,
+                    lowest,
+                    glyphs: BTreeMap::new(),
+                    advances: BTreeMap::new(),
+                    kerning: BTreeMap::new(),
+                });
+                atlas.sources.insert(id, GlyphSource { ttf: content.clone(), cache: self.cache_path(content), saved: 0 });
             },
-            FontObject::MSDF(data, glyphs) => {
-                atlas.upload_part_raw(GenMipmaps::No
-                                      , [0, fy]
-                                      , [aw, 255 / glyphs_per_row]
-                                      , &data);
+            FontObject::MSDF(image, font, ttf) => {
+                atlas.add_msdf_font(id, image, font.clone());
+
+                if let Some(ttf) = ttf {
+                    let saved = font.advances.len();
+                    atlas.sources.insert(id, GlyphSource { ttf: ttf.clone(), cache: self.cache_path(ttf), saved });
+                }
             },
             }
+
+            atlas.load_glyphs(&preloaded, id);
         }
 
-        let tex = atlas.get_raw_texels();
-        image::save_buffer("font_atlas.png", &tex, aw, ah, image::ColorType::Rgb8).unwrap();
+        if let Err(e) = atlas.save_cache() {
+            eprintln!("Cannot cache font atlas: {}", e);
+        }
+
+        Some(atlas)
+    }
+
+    /// Same as `build_atlas`, with the atlas uploaded to a texture.
+    pub fn build<C:GraphicsContext>(&self, ctx: &mut C, sampler: Sampler) -> Option<TextRenderer> {
+        let mut glyphs = self.build_atlas()?;
+        let (aw, ah) = glyphs.atlas_size;
+        let atlas : Texture<Dim2, NormRGB8UI> = Texture::new(ctx, [aw, ah], 0, sampler).ok()?;
+        atlas.upload_raw(GenMipmaps::No, &glyphs.texels).ok()?;
+        glyphs.dirty = None;
 
         Some(TextRenderer {
             atlas,
-            fonts,
-            resolution: res as f32,
+            glyphs,
             text_cache: Cell::new(HashMap::new()),
+            sampler,
         })
     }
 }
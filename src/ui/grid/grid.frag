@@ -0,0 +1,25 @@
+in vec2 pixcoord;
+
+uniform vec2 chunk_size;
+uniform vec4 grid_color;
+uniform vec4 chunk_color;
+
+out vec4 diffuseColor;
+
+// whether a one screen pixel wide line, at every multiple of step, goes through this fragment
+bool on_line(vec2 p, vec2 step)
+{
+    vec2 d = mod(p, step);
+    return any(lessThan(d, fwidth(p)));
+}
+
+void main()
+{
+    if (chunk_color.a > 0.0 && on_line(pixcoord, chunk_size)) {
+        diffuseColor = chunk_color;
+    } else if (grid_color.a > 0.0 && on_line(pixcoord, vec2(1.0, 1.0))) {
+        diffuseColor = grid_color;
+    } else {
+        discard;
+    }
+}
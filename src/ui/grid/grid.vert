@@ -0,0 +1,16 @@
+in vec2 pos;
+in vec2 texPos;
+
+uniform mat3 view;
+uniform vec2 canvas_size;
+
+out vec2 pixcoord;
+
+void main()
+{
+    vec3 fpos = vec3(pos, 1) * view;
+    gl_Position = vec4(fpos.xy, 0, 1.0);
+
+    // position in canvas pixels, ignoring the gaps of exploded mode
+    pixcoord = texPos * canvas_size;
+}
@@ -0,0 +1,31 @@
+use luminance_derive::UniformInterface;
+use crate::color::Color;
+use luminance::{
+    shader::program::Uniform,
+    linear::M33,
+};
+
+/// The grid is drawn over the canvas tessellation (see `canvas::Vertex`), so that it follows the
+/// chunks in exploded mode.
+#[derive(UniformInterface)]
+pub struct ShaderInterface {
+    #[uniform]
+    view: Uniform<M33>,
+    #[uniform]
+    canvas_size: Uniform<[f32; 2]>,
+    #[uniform]
+    chunk_size: Uniform<[f32; 2]>,
+    #[uniform]
+    grid_color: Uniform<[f32; 4]>,
+    #[uniform]
+    chunk_color: Uniform<[f32; 4]>,
+}
+
+/// The pixel grid is only shown from this zoom level (screen pixels per canvas pixel).
+pub const GRID_MIN_ZOOM : f32 = 6.0;
+
+/// Convert a color to the shader representation, or make it fully transparent to hide a grid.
+pub fn uniform_color(color:Color, shown:bool) -> [f32; 4] {
+    let [r, g, b, a] = color.to_f32();
+    [r, g, b, if shown { a } else { 0.0 }]
+}
@@ -1,5 +1,7 @@
 mod vimui;
 pub mod background;
+pub mod grid;
+pub mod palette;
 pub mod selection;
 pub mod uistate;
 
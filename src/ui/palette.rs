@@ -0,0 +1,81 @@
+use crate::{
+    ui::{background::{Vertex, VertexPosition, Color}, uistate::UiState},
+    text::{HAlign, VAlign},
+};
+
+/// Height of a palette entry, in window pixels. Labels are drawn at this size too.
+pub const ROW_HEIGHT : f32 = 24.0;
+const SWATCH_SIZE : f32 = 18.0;
+const PANEL_WIDTH : f32 = 96.0;
+// leaves room for the status line at the top of the window
+const PANEL_TOP : f32 = 32.0;
+const MARGIN : f32 = 6.0;
+
+/// Number of entries in a column of the panel, so that it fits in the window height.
+fn rows_per_column(window_height:f32) -> usize {
+    (((window_height - PANEL_TOP - MARGIN * 2.0) / ROW_HEIGHT) as usize).max(1)
+}
+
+/// Offset from the right of the window of the column of the `i`th entry, and top of its row.
+/// Columns are filled one after the other, from right to left.
+fn entry_position(i:usize, rows:usize) -> (f32, f32) {
+    ((i / rows) as f32 * PANEL_WIDTH, PANEL_TOP + MARGIN + ROW_HEIGHT * (i % rows) as f32)
+}
+
+/// Quads of the palette panel, in normalized device coordinates, to be drawn with the background
+/// program: the panel itself, then a swatch per color, outlined if it is the last color used.
+pub fn render_palette(state:&UiState) -> Vec<Vertex> {
+    let (w, h) = state.window_size;
+    let entries = state.palette_entries();
+    let mut ret = Vec::with_capacity((entries.len() * 2 + 1) * 6);
+
+    let mut quad = |(left, top):(f32, f32), (right, bottom):(f32, f32), color:[f32;3]| {
+        let (left, right) = (left / w * 2.0 - 1.0, right / w * 2.0 - 1.0);
+        let (top, bottom) = (1.0 - top / h * 2.0, 1.0 - bottom / h * 2.0);
+        let color = Color::new(color);
+
+        ret.extend_from_slice(&[
+            Vertex { pos:VertexPosition::new([left,bottom]), color },
+            Vertex { pos:VertexPosition::new([left,top]), color },
+            Vertex { pos:VertexPosition::new([right,top]), color },
+            Vertex { pos:VertexPosition::new([right,top]), color },
+            Vertex { pos:VertexPosition::new([right,bottom]), color },
+            Vertex { pos:VertexPosition::new([left,bottom]), color },
+        ]);
+    };
+
+    let rows = rows_per_column(h);
+    let columns = (entries.len() + rows - 1) / rows;
+    let bottom = PANEL_TOP + ROW_HEIGHT * entries.len().min(rows) as f32 + MARGIN * 2.0;
+    quad((w - PANEL_WIDTH * columns.max(1) as f32, PANEL_TOP), (w, bottom), [0.15, 0.15, 0.15]);
+
+    for (i, (key, color)) in entries.iter().enumerate() {
+        let (offset, row_top) = entry_position(i, rows);
+        let top = row_top + (ROW_HEIGHT - SWATCH_SIZE) * 0.5;
+        let left = w - offset - MARGIN - SWATCH_SIZE;
+
+        if state.last_color == Some(*key) {
+            quad((left - 3.0, top - 3.0), (left + SWATCH_SIZE + 3.0, top + SWATCH_SIZE + 3.0), [1.0, 1.0, 1.0]);
+        }
+
+        let [r, g, b, _] = color.to_f32();
+        quad((left, top), (left + SWATCH_SIZE, top + SWATCH_SIZE), [r, g, b]);
+    }
+
+    ret
+}
+
+/// Key labels of the palette entries, to be drawn at `ROW_HEIGHT` on the left of their swatch.
+pub fn palette_labels(state:&UiState) -> Vec<(String, (HAlign, VAlign))> {
+    let rows = rows_per_column(state.window_size.1);
+
+    state.palette_entries()
+        .iter()
+        .enumerate()
+        .map(|(i, (key, _))| {
+            let (offset, top) = entry_position(i, rows);
+            let right = (offset + MARGIN * 3.0 + SWATCH_SIZE) as usize;
+            (key.to_string(), (HAlign::Right(right), VAlign::Top(top as usize)))
+        })
+        .collect()
+}
@@ -1,29 +1,369 @@
 use std::collections::{HashMap, HashSet};
 use crate::{
     canvas::{self, Canvas},
-    ui::selection as sel,
+    ui::{selection as sel, KeySequence},
     keyboard::CharKeyMod,
+    color::{Color, BlendMode},
     bitmap2d::BitMap2D,
+    sheet::FrameTag,
+    maths::*,
 };
+use glm::Mat3;
 
 const GSIZE : f32 = 0.3;
 
+/// Width of the -1..1 range of normalized device coordinates. Multiplied by `scale`, the inverse
+/// of the window size, it maps window pixels to device coordinates, and by `zoom` on top of that,
+/// canvas pixels.
+const VIEW_SCALE : f32 = 2.0;
+
 pub struct UiState {
     pub filename:Option<String>,
-    pub palette:HashMap<CharKeyMod, (u8, u8, u8, u8)>,
+    pub palette:HashMap<CharKeyMod, Color>,
+    /// Key of the palette color painted last, highlighted in the palette panel.
+    pub last_color:Option<CharKeyMod>,
+    pub palette_panel:bool,
+    /// Keys to which the colors of a loaded palette file are assigned, in order.
+    pub palette_keys:Vec<CharKeyMod>,
+    /// How painting verbs compose colors with the canvas.
+    pub blend:BlendMode,
     pub must_resize:bool,
     pub scale:(f32, f32),
+    /// Number of screen pixels per canvas pixel.
     pub zoom:f32,
     pub center:(f32, f32),
     pub canvas:Canvas,
     pub visual_type:VisualType,
     pub window_size:(f32, f32),
     pub selection:HashSet<(usize, usize)>,
+    /// Size of the chunks, which are also the frames of the animation, read row by row.
     pub chunk_size:(usize, usize),
+    /// How long the frames without an entry in `frame_delays` are shown, in milliseconds.
+    pub frame_delay:u32,
+    pub frame_delays:HashMap<usize, u32>,
+    /// Named ranges of frames, written in the sprite sheet descriptions.
+    pub frame_tags:Vec<FrameTag>,
     pub exploded:bool,
+    pub grid:bool,
+    pub chunk_grid:bool,
+    pub grid_color:Color,
+    pub chunk_grid_color:Color,
+    /// File to which the next frame should be rendered by the software renderer.
+    pub screenshot:Option<String>,
+    /// Message shown above the mode line, cleared when entering command mode.
+    pub message:Option<String>,
 }
 
 impl UiState {
+    /// Apply a vim-like option assignment: `name` or `noname` for boolean options (`name!` toggles
+    /// them), and `name=value` for the others.
+    pub fn set_option(&mut self, opt:&str) -> Result<(), String> {
+        let indexed = match opt {
+            "indexed" => Some(true),
+            "noindexed" => Some(false),
+            "indexed!" => Some(!self.canvas.is_indexed()),
+            _ => None,
+        };
+
+        if let Some(indexed) = indexed {
+            self.set_indexed(indexed)?;
+        } else if let Some(i) = opt.find('=') {
+            let (name, value) = (&opt[..i], &opt[i + 1..]);
+            let color = match name {
+                "gridcolor" => &mut self.grid_color,
+                "chunkgridcolor" => &mut self.chunk_grid_color,
+                "blend" => {
+                    self.blend = value.parse()?;
+                    return Ok(())
+                },
+                "delay" => {
+                    self.frame_delay = value.parse().map_err(|_| format!("invalid delay: {}", value))?;
+                    return Ok(())
+                },
+                "palettekeys" => {
+                    let keys = value.parse::<KeySequence>().map_err(|e| e.to_string())?;
+                    self.palette_keys = keys.keys().to_vec();
+                    return Ok(())
+                },
+                _ => return Err(format!("unknown option: {}", name)),
+            };
+            *color = value.parse().map_err(|e:crate::color::ParseColorError| e.to_string())?;
+        } else if let Some(name) = opt.strip_suffix('!') {
+            let flag = self.bool_option(name).ok_or_else(|| format!("unknown option: {}", name))?;
+            *flag = !*flag;
+        } else if let Some(flag) = self.bool_option(opt) {
+            *flag = true;
+        } else if let Some(name) = opt.strip_prefix("no") {
+            let flag = self.bool_option(name).ok_or_else(|| format!("unknown option: {}", opt))?;
+            *flag = false;
+        } else {
+            return Err(format!("unknown option: {}", opt))
+        }
+
+        Ok(())
+    }
+
+    /// Switch the canvas between RGBA and indexed storage. The indexed palette starts with the
+    /// colors of the UI palette, in the same order.
+    pub fn set_indexed(&mut self, indexed:bool) -> Result<(), String> {
+        if !indexed {
+            self.canvas.to_rgba();
+        } else if !self.canvas.is_indexed() {
+            let order : Vec<Color> = self.palette_entries().iter().map(|(_, c)| *c).collect();
+            self.canvas.to_indexed(&order)?;
+        }
+        Ok(())
+    }
+
+    /// Assign a color to a palette key. On an indexed canvas, the pixels of the previous color
+    /// of the key take the new color.
+    pub fn set_color(&mut self, key:CharKeyMod, color:Color) {
+        let old = self.palette.insert(key, color);
+
+        let index = self.canvas.indexed().and_then(|i| i.palette.iter().position(|c| Some(*c) == old));
+        if let Some(index) = index {
+            self.canvas.set_palette_color(index, color);
+        }
+    }
+
+    fn bool_option(&mut self, name:&str) -> Option<&mut bool> {
+        match name {
+            "grid" => Some(&mut self.grid),
+            "chunkgrid" => Some(&mut self.chunk_grid),
+            "exploded" => Some(&mut self.exploded),
+            "palette" => Some(&mut self.palette_panel),
+            _ => None,
+        }
+    }
+
+    /// Palette entries, ordered as `palette_keys`, then by key name for the keys not in it.
+    pub fn palette_entries(&self) -> Vec<(CharKeyMod, Color)> {
+        let mut entries : Vec<_> = self.palette.iter().map(|(k, c)| (*k, *c)).collect();
+        entries.sort_by_key(|(k, _)| {
+            let pos = self.palette_keys.iter().position(|p| p == k);
+            (pos.unwrap_or(self.palette_keys.len()), k.to_string())
+        });
+        entries
+    }
+
+    /// Replace the palette by a list of colors, assigned to `palette_keys` in order. Returns the
+    /// number of colors which had no key left.
+    pub fn set_palette(&mut self, colors:&[Color]) -> usize {
+        self.palette = self.palette_keys.iter().cloned().zip(colors.iter().cloned()).collect();
+        self.last_color = None;
+        colors.len().saturating_sub(self.palette_keys.len())
+    }
+
+    /// Color given by the name of a palette key, or in any syntax `Color` parses.
+    pub fn resolve_color(&self, s:&str) -> Result<Color, String> {
+        let key = s.parse::<CharKeyMod>().ok().and_then(|k| self.palette.get(&k));
+        match key {
+            Some(color) => Ok(*color),
+            None => s.parse::<Color>().map_err(|e| e.to_string()),
+        }
+    }
+
+    /// Pixels the color commands work on: the selection, or the whole canvas if nothing is
+    /// selected.
+    pub fn target_pixels(&self) -> Vec<(usize, usize)> {
+        if self.selection.is_empty() {
+            let (w, h) = self.canvas.size();
+            (0..h).flat_map(|y| (0..w).map(move |x| (x, y))).collect()
+        } else {
+            self.selection.iter().cloned().collect()
+        }
+    }
+
+    /// Distinct colors of the target pixels, with their number of pixels, most frequent first.
+    pub fn used_colors(&self) -> Vec<(Color, usize)> {
+        let mut counts = HashMap::new();
+        for (x, y) in self.target_pixels() {
+            *counts.entry(self.canvas.get_pixel_color(x, y)).or_insert(0) += 1;
+        }
+
+        let mut colors : Vec<_> = counts.into_iter().collect();
+        colors.sort_by_key(|&(c, n)| (std::cmp::Reverse(n), <(u8, u8, u8, u8)>::from(c)));
+        colors
+    }
+
+    /// Size of the animation frames: the chunks, or the canvas if it is smaller.
+    pub fn frame_size(&self) -> (usize, usize) {
+        let (w, h) = self.canvas.size();
+        (self.chunk_size.0.min(w).max(1), self.chunk_size.1.min(h).max(1))
+    }
+
+    /// Number of frames, only counting the chunks which are not cut by the canvas border.
+    pub fn frame_count(&self) -> usize {
+        let ((w, h), (fw, fh)) = (self.canvas.size(), self.frame_size());
+        (w / fw) * (h / fh)
+    }
+
+    /// Index of the frame containing a pixel, or `None` if it is in a chunk cut by the canvas
+    /// border, which is not a frame.
+    pub fn frame_at(&self, (x, y):(usize, usize)) -> Option<usize> {
+        let ((w, h), (fw, fh)) = (self.canvas.size(), self.frame_size());
+        let (cols, rows) = (w / fw, h / fh);
+        let (col, row) = (x / fw, y / fh);
+        if col < cols && row < rows { Some(row * cols + col) } else { None }
+    }
+
+    /// Delay of a frame, in milliseconds.
+    pub fn frame_delay(&self, frame:usize) -> u32 {
+        self.frame_delays.get(&frame).copied().unwrap_or(self.frame_delay)
+    }
+
+    /// Pixels of a frame, row by row.
+    pub fn frame_pixels(&self, frame:usize) -> Vec<Color> {
+        let ((w, _), (fw, fh)) = (self.canvas.size(), self.frame_size());
+        let cols = w / fw;
+        let (x, y) = ((frame % cols) * fw, (frame / cols) * fh);
+        self.canvas.region((x, y), (x + fw - 1, y + fh - 1))
+    }
+
+    /// Scaling part of `canvas_view`.
+    fn view_scale(&self) -> (f32, f32) {
+        (self.scale.0 * VIEW_SCALE * self.zoom, -self.scale.1 * VIEW_SCALE * self.zoom)
+    }
+
+    /// Transformation from canvas coordinates to normalized device coordinates.
+    pub fn canvas_view(&self) -> Mat3 {
+        let (scale_x, scale_y) = self.view_scale();
+
+        scale(scale_x, scale_y) * translate(self.center.0, self.center.1)
+    }
+
+    /// Transformation from window pixels to normalized device coordinates, used for the UI text.
+    pub fn text_view(&self) -> Mat3 {
+        let center_x = self.window_size.0 * 0.5;
+        let center_y = self.window_size.1 * 0.5;
+        let scale_x = self.scale.0 * VIEW_SCALE;
+        let scale_y = -self.scale.1 * VIEW_SCALE;
+
+        scale(scale_x, scale_y) * translate(-center_x, -center_y - 10.0)
+    }
+
+    /// Inverse of `canvas_view` applied to a position in window pixels: gives the canvas
+    /// coordinates under that position.
+    pub fn unproject(&self, (px, py):(f32, f32)) -> (f32, f32) {
+        let (w, h) = self.window_size;
+        let (nx, ny) = (px / w * 2.0 - 1.0, 1.0 - py / h * 2.0);
+        let (scale_x, scale_y) = self.view_scale();
+
+        (nx / scale_x - self.center.0, ny / scale_y - self.center.1)
+    }
+
+    /// `canvas_view` applied to canvas coordinates, giving a position in window pixels.
+    pub fn project(&self, (cx, cy):(f32, f32)) -> (f32, f32) {
+        let (w, h) = self.window_size;
+        let (scale_x, scale_y) = self.view_scale();
+        let (nx, ny) = ((cx + self.center.0) * scale_x, (cy + self.center.1) * scale_y);
+
+        ((nx + 1.0) * 0.5 * w, (1.0 - ny) * 0.5 * h)
+    }
+
+    /// Canvas coordinates of the topleft corner of a pixel, taking the gaps between chunks into
+    /// account in exploded mode.
+    pub fn pixel_position(&self, x:usize, y:usize) -> (f32, f32) {
+        let (gx, gy) = if self.exploded {
+            ((x / self.chunk_size.0) as f32, (y / self.chunk_size.1) as f32)
+        } else {
+            (0.0, 0.0)
+        };
+
+        (x as f32 + gx * GSIZE, y as f32 + gy * GSIZE)
+    }
+
+    /// Canvas coordinates of the center of a pixel.
+    fn pixel_center(&self, (x, y):(usize, usize)) -> (f32, f32) {
+        let (px, py) = self.pixel_position(x, y);
+        (px + 0.5, py + 0.5)
+    }
+
+    /// Size of the displayed canvas in canvas coordinates, gaps included.
+    fn canvas_extent(&self) -> (f32, f32) {
+        let (w, h) = self.canvas.size();
+        let (ex, ey) = self.pixel_position(w.max(1) - 1, h.max(1) - 1);
+        (ex + 1.0, ey + 1.0)
+    }
+
+    /// The canvas pixel under a position in window pixels, if any. In exploded mode, the gaps
+    /// between chunks don't belong to any pixel.
+    pub fn pixel_at(&self, pointer:(f32, f32)) -> Option<(usize, usize)> {
+        let (cx, cy) = self.unproject(pointer);
+        let (w, h) = self.canvas.size();
+
+        let axis = |c:f32, chunk:usize, max:usize| {
+            let c = if self.exploded {
+                let stride = chunk as f32 + GSIZE;
+                let i = (c / stride).floor();
+                let local = c - i * stride;
+                if local >= chunk as f32 { return None }
+                i * chunk as f32 + local
+            } else {
+                c
+            };
+
+            if c < 0.0 || c >= max as f32 { None } else { Some(c as usize) }
+        };
+
+        Some((axis(cx, self.chunk_size.0, w)?, axis(cy, self.chunk_size.1, h)?))
+    }
+
+    /// Set the zoom level while keeping the canvas point under `pointer` at the same place on
+    /// screen.
+    pub fn zoom_around(&mut self, pointer:(f32, f32), zoom:f32) {
+        let (bx, by) = self.unproject(pointer);
+        self.zoom = zoom;
+        let (ax, ay) = self.unproject(pointer);
+        self.center.0 += ax - bx;
+        self.center.1 += ay - by;
+    }
+
+    /// Set the zoom level while keeping `pixel` at the same place on screen, then scroll the view if
+    /// that was not enough to keep it visible.
+    pub fn zoom_on_pixel(&mut self, pixel:(usize, usize), zoom:f32) {
+        let pos = self.project(self.pixel_center(pixel));
+        self.zoom_around(pos, zoom);
+        self.keep_visible(pixel);
+    }
+
+    /// Center the view on a pixel.
+    pub fn center_on(&mut self, pixel:(usize, usize)) {
+        let (px, py) = self.pixel_center(pixel);
+        self.center = (-px, -py);
+    }
+
+    /// Center the view on `pixel` if it is not entirely on screen.
+    pub fn keep_visible(&mut self, pixel:(usize, usize)) {
+        let (x, y) = self.pixel_position(pixel.0, pixel.1);
+        let (l, t) = self.project((x, y));
+        let (r, b) = self.project((x + 1.0, y + 1.0));
+        let (w, h) = self.window_size;
+
+        if l < 0.0 || t < 0.0 || r > w || b > h {
+            self.center_on(pixel);
+        }
+    }
+
+    /// Center the canvas and pick the biggest zoom level (see `zoom_level`) at which it fits in
+    /// the window.
+    pub fn zoom_fit(&mut self) {
+        let (ew, eh) = self.canvas_extent();
+        let (w, h) = self.window_size;
+        let fit = (w / ew).min(h / eh);
+
+        self.zoom = if fit >= 1.0 { fit.floor() } else { 1.0 / (1.0 / fit).ceil() };
+        self.center = (-ew * 0.5, -eh * 0.5);
+    }
+
+    /// Move the canvas so that the point under `from` ends up under `to`.
+    pub fn pan(&mut self, from:(f32, f32), to:(f32, f32)) {
+        let (fx, fy) = self.unproject(from);
+        let (tx, ty) = self.unproject(to);
+        self.center.0 += tx - fx;
+        self.center.1 += ty - fy;
+    }
+
     pub fn render_canvas(&self) -> Vec<canvas::Vertex> {
         use canvas::*;
         let (icw, ich) = self.canvas.size();             // canvas size in pixels
@@ -106,18 +446,12 @@ impl UiState {
             let (tx, ty) = (cs*(itx as f32 )/ ats, cs*(ity as f32) / ats);
 
             // compute the color we are on
-            let (r, g, b, _) = self.canvas.get_pixel_color(x, y);
+            let Color { r, g, b, .. } = self.canvas.get_pixel_color(x, y);
             let scol = [r, g, b];
 
 
             // if exploded, we need to add the explosion gap to the cell coordinates
-            let (gx, gy) = if self.exploded {
-                ((x / self.chunk_size.0) as f32, (y / self.chunk_size.1) as f32)
-            } else {
-                (0.0, 0.0)
-            };
-
-            let (px, py) = (x as f32 + gx * GSIZE, y as f32 + gy * GSIZE);
+            let (px, py) = self.pixel_position(x, y);
 
             if !(itx == 0 && ity == 0) {
                 // send the cell's vertice (two triangles for a square)
@@ -160,6 +494,25 @@ impl UiState {
     }
 }
 
+/// Zoom level `steps` integer pixel ratios away from `zoom`. Above 1:1, levels are whole numbers
+/// of screen pixels per canvas pixel; below, they are 1/2, 1/3, 1/4, ...
+pub fn zoom_level(zoom:f32, steps:i32) -> f32 {
+    const EPS : f32 = 1e-3;
+    let mut zoom = zoom;
+
+    for _ in 0..steps.abs() {
+        zoom = if steps > 0 {
+            if zoom >= 1.0 - EPS { (zoom + EPS).floor() + 1.0 }
+            else { 1.0 / (((1.0 / zoom) - EPS).ceil() - 1.0).max(1.0) }
+        } else {
+            if zoom > 1.0 + EPS { ((zoom - EPS).ceil() - 1.0).max(1.0) }
+            else { 1.0 / (((1.0 / zoom) + EPS).floor() + 1.0) }
+        };
+    }
+
+    zoom
+}
+
 pub enum VisualType {
     Square,
     Circle,
@@ -255,3 +608,143 @@ fn even_radius_andres<T:BitMap2D>(set:&mut T, (x0, y0):(isize, isize), r:f32) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ui_state(w:usize, h:usize) -> UiState {
+        UiState {
+            filename: None,
+            must_resize: false,
+            scale: (1.0 / 640.0, 1.0 / 480.0),
+            zoom: 8.0,
+            canvas: Canvas::new(w, h),
+            center: (-8.0, -8.0),
+            visual_type: VisualType::Square,
+            palette: HashMap::new(),
+            last_color:None,
+            palette_panel:false,
+            blend:BlendMode::Normal,
+            palette_keys:Vec::new(),
+            window_size:(640.0, 480.0),
+            selection:HashSet::new(),
+            chunk_size:(4, 4),
+            frame_delay:100,
+            frame_delays:HashMap::new(),
+            frame_tags:Vec::new(),
+            exploded:false,
+            grid:false,
+            chunk_grid:false,
+            grid_color:Color::rgba(64, 64, 64, 96),
+            chunk_grid_color:Color::rgba(255, 255, 255, 160),
+            screenshot:None,
+            message:None,
+        }
+    }
+
+    #[test]
+    fn frames_are_whole_chunks() {
+        // 2 whole chunks per row and a partial column, 1 whole row and a partial row
+        let state = ui_state(10, 6);
+        assert_eq!(state.frame_count(), 2);
+        assert_eq!(state.frame_at((0, 0)), Some(0));
+        assert_eq!(state.frame_at((7, 3)), Some(1));
+        assert_eq!(state.frame_at((8, 0)), None);
+        assert_eq!(state.frame_at((0, 4)), None);
+
+        // A canvas smaller than a chunk is a single frame
+        let state = ui_state(3, 2);
+        assert_eq!(state.frame_count(), 1);
+        assert_eq!(state.frame_at((2, 1)), Some(0));
+    }
+
+    #[test]
+    fn pixels_under_their_projection() {
+        let mut state = ui_state(10, 6);
+        state.center = (-3.3, -2.1);
+
+        for &zoom in &[1.0, 0.5, 3.0, 7.5] {
+            state.zoom = zoom;
+            for &exploded in &[false, true] {
+                state.exploded = exploded;
+                for y in 0..6 {
+                    for x in 0..10 {
+                        let center = state.pixel_center((x, y));
+                        let pixel = state.pixel_at(state.project(center));
+                        assert_eq!(pixel, Some((x, y)), "zoom {} exploded {}", zoom, exploded);
+
+                        let (ux, uy) = state.unproject(state.project(center));
+                        assert!((ux - center.0).abs() < 1e-3 && (uy - center.1).abs() < 1e-3);
+                    }
+                }
+            }
+        }
+
+        // outside the canvas, and in the gaps between chunks
+        state.zoom = 8.0;
+        assert_eq!(state.pixel_at(state.project((-0.5, 0.5))), None);
+        assert_eq!(state.pixel_at(state.project((state.canvas_extent().0 + 0.1, 0.5))), None);
+        assert_eq!(state.pixel_at(state.project((4.0 + GSIZE * 0.5, 0.5))), None);
+        state.exploded = false;
+        assert_eq!(state.pixel_at(state.project((4.0 + GSIZE * 0.5, 0.5))), Some((4, 0)));
+    }
+
+    #[test]
+    fn zoom_levels() {
+        assert_eq!(zoom_level(1.0, 1), 2.0);
+        assert_eq!(zoom_level(8.0, 3), 11.0);
+        assert_eq!(zoom_level(2.0, -1), 1.0);
+        assert_eq!(zoom_level(1.0, -1), 0.5);
+        assert_eq!(zoom_level(0.5, -1), 1.0 / 3.0);
+        assert_eq!(zoom_level(1.0 / 3.0, 2), 1.0);
+        assert_eq!(zoom_level(4.0, 0), 4.0);
+
+        // zoom levels which are not integer ratios go to the next one
+        assert_eq!(zoom_level(2.5, 1), 3.0);
+        assert_eq!(zoom_level(2.5, -1), 2.0);
+        assert_eq!(zoom_level(0.4, 1), 0.5);
+    }
+
+    #[test]
+    fn zoom_around_keeps_the_anchor() {
+        let mut state = ui_state(10, 6);
+        state.center = (-5.0, -3.0);
+
+        for &exploded in &[false, true] {
+            state.exploded = exploded;
+            for &pointer in &[(100.0, 50.0), (320.0, 240.0), (613.0, 401.5)] {
+                let anchor = state.unproject(pointer);
+                for &zoom in &[1.0, 0.25, 5.0, 16.0] {
+                    state.zoom_around(pointer, zoom);
+                    assert_eq!(state.zoom, zoom);
+                    let (x, y) = state.unproject(pointer);
+                    assert!((x - anchor.0).abs() < 1e-3 && (y - anchor.1).abs() < 1e-3);
+                }
+            }
+        }
+
+        // the pixel under the cursor stays under it
+        state.zoom = 8.0;
+        let pixel = (7, 2);
+        let pos = state.project(state.pixel_center(pixel));
+        state.zoom_on_pixel(pixel, 24.0);
+        assert_eq!(state.pixel_at(pos), Some(pixel));
+    }
+
+    #[test]
+    fn zoom_fit() {
+        let mut state = ui_state(10, 6);
+        state.zoom_fit();
+        assert_eq!((state.zoom, state.center), (64.0, (-5.0, -3.0)));
+
+        // smaller than 1:1, at a whole ratio: 500 window pixels wide
+        let mut state = ui_state(2000, 100);
+        state.zoom_fit();
+        assert_eq!((state.zoom, state.center), (0.25, (-1000.0, -50.0)));
+        assert_eq!(state.pixel_at((69.0, 240.0)), None);
+        assert_eq!(state.pixel_at((71.0, 240.0)), Some((4, 50)));
+        assert_eq!(state.pixel_at((569.0, 240.0)), Some((1996, 50)));
+        assert_eq!(state.pixel_at((571.0, 240.0)), None);
+    }
+}
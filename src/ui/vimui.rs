@@ -1,6 +1,6 @@
-use luminance_glfw::{GlfwSurface, Surface, WindowEvent, Action, Key};
-use std::{rc::Rc, collections::{HashMap, HashSet}};
-use crate::keyboard::{ModSet, KeyboardLayout, CharKeyMod, CharKey, Mod, azerty};
+use luminance_glfw::{GlfwSurface, Surface, WindowEvent, Action, Key, MouseButton};
+use std::{fmt, rc::Rc, str::FromStr, collections::{HashMap, HashSet}};
+use crate::keyboard::{ModSet, KeyboardLayout, CharKeyMod, CharKey, Mod, ParseKeyError, azerty};
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub enum Mode {
@@ -15,30 +15,49 @@ pub struct KeySequence {
     seq:Vec<CharKeyMod>,
 }
 
+/// Split a key sequence into the notation of each of its keys, e.g. `<Esc>hi` gives `<Esc>`, `h`
+/// and `i`. A `<` which doesn't open a key name stands for itself.
+fn split_keys(s:&str) -> impl Iterator<Item=&str> {
+    let mut rest = s;
+    std::iter::from_fn(move || {
+        let c = rest.chars().next()?;
+        let len = match rest.find('>') {
+            Some(end) if c == '<' && end > 1 => end + 1,
+            _ => c.len_utf8(),
+        };
+        let (key, tail) = rest.split_at(len);
+        rest = tail;
+        Some(key)
+    })
+}
+
+impl KeySequence {
+    pub fn keys(&self) -> &[CharKeyMod] {
+        &self.seq
+    }
+}
+
+impl FromStr for KeySequence {
+    type Err = ParseKeyError;
+
+    fn from_str(s:&str) -> Result<Self, ParseKeyError> {
+        let seq = split_keys(s).map(str::parse).collect::<Result<_, _>>()?;
+        Ok(Self { seq })
+    }
+}
+
 impl From<&str> for KeySequence {
     fn from(s:&str) -> Self {
-        let mut chars = s.chars();
-        let mut seq : Vec<CharKeyMod> = Vec::new();
-
-        while let Some(c) = chars.next() {
-            if c == '<' {
-                let mut s = '<'.to_string();
-                let chars = chars.by_ref();
-                while let Some(c) = chars.next() {
-                    if c == '>' {
-                        break;
-                    }
-                    s.push(c);
-                }
+        Self { seq: split_keys(s).map(CharKeyMod::from).collect() }
+    }
+}
 
-                s.push('>');
-                seq.push(CharKeyMod::from(s.as_ref()));
-            } else {
-                seq.push(CharKeyMod::from(c.to_string().as_ref()));
-            }
+impl fmt::Display for KeySequence {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        for key in &self.seq {
+            write!(f, "{}", key)?;
         }
-
-        Self { seq }
+        Ok(())
     }
 }
 
@@ -46,22 +65,46 @@ pub type UiCommand<T> = dyn Fn(&mut Ui<T>, &mut T, &Vec<&str>);
 pub type UiVerb<T> = dyn Fn(&mut Ui<T>, &mut T, Option<&HashSet<(usize, usize)>>);
 pub type UiObject<T> = dyn Fn(&mut Ui<T>, &T, &mut HashSet<(usize, usize)>);
 pub type UiCharProcessor<T> = dyn Fn(&mut Ui<T>, &mut T, CharKeyMod);
+pub type UiPointerListener<T> = dyn Fn(&mut Ui<T>, &mut T, PointerEvent);
+
+/// Mouse events, with pointer positions in window pixels.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PointerEvent {
+    Press(MouseButton, (f32, f32)),
+    /// The pointer moved from the first to the second position while the button was held.
+    Drag(MouseButton, (f32, f32), (f32, f32)),
+    Release(MouseButton, (f32, f32)),
+    /// Vertical wheel offset at the given position.
+    Scroll((f32, f32), f32),
+}
 
 pub struct Ui<T> {
     commands: HashMap<String, Rc<UiCommand<T>>>,
+    // commands which take the rest of the line as their only argument, see `add_raw_command`
+    raw_commands: HashSet<String>,
     verbs: HashMap<CharKeyMod, (bool, Rc<UiVerb<T>>)>,
     objects: HashMap<CharKeyMod, Rc<UiObject<T>>>,
+    // verbs typed as a key sequence, which take the key typed after it as argument
+    key_verbs: HashMap<Vec<CharKeyMod>, Rc<UiCharProcessor<T>>>,
     char_processor: Rc<UiCharProcessor<T>>,
 
     bindings: HashMap<(CharKeyMod, Mode), KeySequence>,
     modset:ModSet,
 
     window_event_listener: Option<Rc<dyn Fn(&mut T, WindowEvent)>>,
+    pointer_listener: Option<Rc<UiPointerListener<T>>>,
+    // last known pointer position, and the mouse button being held if any
+    pointer: (f32, f32),
+    held_button: Option<MouseButton>,
     // buffer for storing unprocessed chars waiting
     buffer: String,
 
     // typed verb waiting for an object to come (if transitive)
     verb: Option<(usize, Rc<UiVerb<T>>)>,
+    // keys typed so far which are the beginning of a key verb, and the key verb waiting for its
+    // argument
+    key_prefix: Vec<CharKeyMod>,
+    key_verb: Option<Rc<UiCharProcessor<T>>>,
 
     mode: Mode,
     running: bool,
@@ -76,17 +119,24 @@ impl<T> Ui<T> {
             layout: azerty::layout(),
             modset: ModSet::empty(),
             commands: HashMap::new(),
+            raw_commands: HashSet::new(),
             verbs: HashMap::new(),
             objects: HashMap::new(),
+            key_verbs: HashMap::new(),
             bindings: HashMap::new(),
 
             window_event_listener: None,
+            pointer_listener: None,
+            pointer: (0.0, 0.0),
+            held_button: None,
 
             // processor of Insertion mode characters
             char_processor: Rc::new(f),
 
             buffer: String::new(),
             verb: None,
+            key_prefix: Vec::new(),
+            key_verb: None,
 
             mode: Mode::Normal,
             running: true,
@@ -139,6 +189,27 @@ impl<T> Ui<T> {
                     }
                 },
 
+                WindowEvent::CursorPos(x, y) => {
+                    let from = std::mem::replace(&mut self.pointer, (x as f32, y as f32));
+                    if let Some(button) = self.held_button {
+                        self.send_pointer_event(env, PointerEvent::Drag(button, from, self.pointer));
+                    }
+                },
+
+                WindowEvent::MouseButton(button, Action::Press, _) => {
+                    self.held_button = Some(button);
+                    self.send_pointer_event(env, PointerEvent::Press(button, self.pointer));
+                },
+
+                WindowEvent::MouseButton(button, Action::Release, _) => {
+                    self.held_button = None;
+                    self.send_pointer_event(env, PointerEvent::Release(button, self.pointer));
+                },
+
+                WindowEvent::Scroll(_, dy) => {
+                    self.send_pointer_event(env, PointerEvent::Scroll(self.pointer, dy as f32));
+                },
+
                 e => {
                     let _ = self.window_event_listener.as_ref().map(|f| {
                         let f = f.clone();
@@ -151,11 +222,29 @@ impl<T> Ui<T> {
         self.running
     }
 
-    fn launch_command(&mut self, env:&mut T, command:String) {
-        let mut words = command.split_whitespace();
+    fn send_pointer_event(&mut self, env:&mut T, e:PointerEvent) {
+        if let Some(f) = self.pointer_listener.as_ref() {
+            let f = f.clone();
+            f(self, env, e)
+        }
+    }
 
-        let name = words.next().unwrap();
-        let args = words.collect();
+    fn launch_command(&mut self, env:&mut T, command:String) {
+        let command = command.trim_start();
+        let name_len = command.find(|c:char| !c.is_alphanumeric()).unwrap_or(command.len());
+
+        // raw commands such as `s/old/new/` take the rest of the line, starting with the delimiter,
+        // as their only argument
+        let raw = self.raw_commands.contains(&command[..name_len]);
+        let (name, args) = if raw && command[name_len..].starts_with(|c:char| c.is_ascii_punctuation()) {
+            (&command[..name_len], vec![&command[name_len..]])
+        } else {
+            let mut words = command.split_whitespace();
+            match words.next() {
+                Some(name) => (name, words.collect()),
+                None => return,
+            }
+        };
 
         if let Some(command) = self.commands.get(name) {
             let command = command.clone();
@@ -173,6 +262,8 @@ impl<T> Ui<T> {
                 self.set_mode(Mode::Normal);
                 self.buffer.clear();
                 self.verb = None;
+                self.key_prefix.clear();
+                self.key_verb = None;
                 // We added the possibility to bind Escape key to a verb
                 if let Some((_, action)) = self.verbs.get(&CharKeyMod { key:c, mods }) {
                     (action.clone())(self, env, None);
@@ -192,13 +283,31 @@ impl<T> Ui<T> {
                 processor(self, env, CharKeyMod { key:c, mods })
             },
 
+            // argument of a key verb, which may be any key, digits included
+            c if self.key_verb.is_some() => {
+                let action = self.key_verb.take().unwrap();
+                action(self, env, CharKeyMod { key:c, mods })
+            },
+
             // number in non-insertion mode
             CharKey::Char(c) if c.is_ascii_digit() => {
                 self.buffer.push(c);
             },
 
+            // key which continues the name of a key verb
+            c if (self.mode == Mode::Normal || self.mode == Mode::Visual) && self.continues_key_verb(CharKeyMod { key:c, mods }) => {
+                self.key_prefix.push(CharKeyMod { key:c, mods });
+
+                if let Some(action) = self.key_verbs.get(&self.key_prefix) {
+                    self.key_verb = Some(action.clone());
+                    self.key_prefix.clear();
+                    self.buffer.clear();
+                }
+            },
+
             // any character in normal mode
             c if self.mode == Mode::Normal || self.mode == Mode::Visual => {
+                self.key_prefix.clear();
                 // parse count
                 let count = if self.buffer.len() == 0 { 1 }
                             else { self.buffer.parse().unwrap() };
@@ -275,6 +384,23 @@ impl<T> Ui<T> {
         let _ = self.objects.insert(obj.into(), Rc::new(f));
     }
 
+    /// Add a verb named by a key sequence, e.g. `gp`, which takes the key typed after it as
+    /// argument.
+    pub fn add_key_verb<S, F>(&mut self, name:S, f:F)
+        where F : Fn(&mut Ui<T>, &mut T, CharKeyMod) + 'static,
+              S : Into<KeySequence>,
+    {
+        let _ = self.key_verbs.insert(name.into().seq, Rc::new(f));
+    }
+
+    /// Whether the typed key verb prefix followed by this key is the beginning of a key verb.
+    fn continues_key_verb(&self, key:CharKeyMod) -> bool {
+        let n = self.key_prefix.len();
+        self.key_verbs
+            .keys()
+            .any(|name| name.len() > n && name[..n] == self.key_prefix[..] && name[n] == key)
+    }
+
     pub fn add_command<S, F>(&mut self, name:S, f:F)
         where F : Fn(&mut Ui<T>, &mut T, &Vec<&str>) + 'static,
               S : Into<String>,
@@ -282,6 +408,17 @@ impl<T> Ui<T> {
         let _ = self.commands.insert(name.into(), Rc::new(f));
     }
 
+    /// Same as `add_command`, for a vim-like command such as `s/old/new/` which is directly
+    /// followed by a delimiter. Its argument is the rest of the line, starting with the delimiter.
+    pub fn add_raw_command<S, F>(&mut self, name:S, f:F)
+        where F : Fn(&mut Ui<T>, &mut T, &Vec<&str>) + 'static,
+              S : Into<String>,
+    {
+        let name = name.into();
+        self.raw_commands.insert(name.clone());
+        self.add_command(name, f);
+    }
+
     pub fn bind_key<K:Into<CharKeyMod>, S:Into<KeySequence>>(&mut self, k:K, mode:Mode, phrase:S) {
         let k = k.into();
         let s = phrase.into();
@@ -296,6 +433,15 @@ impl<T> Ui<T> {
         self.cursor
     }
 
+    /// Move the cursor to the given pixel. Outside of visual mode, this also moves the anchor of
+    /// the next visual selection.
+    pub fn set_cursor(&mut self, x:usize, y:usize) {
+        self.cursor = (x, y);
+        if self.mode != Mode::Visual {
+            self.saved_cursor = self.cursor
+        }
+    }
+
     pub fn wrapping_displace(&mut self, dx:isize, dy:isize, w:usize, h:usize) {
         self.cursor.0 = ((self.cursor.0 as isize).wrapping_add(dx) as usize).min(w - 1);
         self.cursor.1 = ((self.cursor.1 as isize).wrapping_add(dy) as usize).min(h - 1);
@@ -335,7 +481,102 @@ impl<T> Ui<T> {
         }
     }
 
+    pub fn set_pointer_listener<F:Fn(&mut Ui<T>, &mut T, PointerEvent) + 'static>(&mut self, f:Option<F>) {
+        match f {
+            Some(f) => {
+                self.pointer_listener = Some(Rc::new(f));
+            },
+            None => self.pointer_listener = None,
+        }
+    }
+
     pub fn get_buffer(&self) -> &String {
         &self.buffer
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic sequences of keys, with any modifiers, from a xorshift generator.
+    fn sequences() -> Vec<Vec<CharKeyMod>> {
+        let keys : Vec<CharKey> = "aZ0 <>-:é".chars().map(CharKey::Char)
+            .chain(vec![CharKey::Special(0), CharKey::Special(24), CharKey::Special(99)])
+            .collect();
+        let mut state = 0x2545_f491_u32;
+        let mut next = move |n:usize| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as usize % n
+        };
+
+        (0..500)
+            .map(|_| {
+                let len = 1 + next(8);
+                (0..len)
+                    .map(|_| {
+                        let mods = [ModSet::empty(), ModSet::shift(), ModSet::control(), ModSet::alt(), ModSet::altgr()];
+                        let mut set = mods[next(mods.len())];
+                        if next(4) == 0 {
+                            set.set(Mod::Control);
+                        }
+                        CharKeyMod { key:keys[next(keys.len())], mods:set }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn key_sequence_round_trip() {
+        for seq in sequences() {
+            let seq = KeySequence { seq };
+            assert_eq!(seq.to_string().parse::<KeySequence>(), Ok(KeySequence { seq:seq.seq.clone() }), "{}", seq);
+        }
+    }
+
+    #[test]
+    fn key_sequence_notation() {
+        let seq : KeySequence = "<Esc>hi<C-w>".parse().unwrap();
+        assert_eq!(seq.keys().len(), 4);
+        assert_eq!(seq.to_string(), "<Esc>hi<C-w>");
+
+        // a `<` which doesn't open a key name stands for itself
+        let seq : KeySequence = "a<b".parse().unwrap();
+        assert_eq!(seq.keys()[1], CharKeyMod { key:CharKey::Char('<'), mods:ModSet::empty() });
+    }
+
+    #[test]
+    fn invalid_key_sequence() {
+        for s in &["<Foo>", "a<C-Foo>", "<X-a>b", "<C->"] {
+            assert!(s.parse::<KeySequence>().is_err(), "{:?} parsed", s);
+        }
+    }
+
+    #[test]
+    fn command_arguments() {
+        type Log = Vec<(String, Vec<String>)>;
+        let logger = |name:&'static str| move |_:&mut Ui<Log>, log:&mut Log, args:&Vec<&str>| {
+            log.push((name.to_string(), args.iter().map(|a| a.to_string()).collect()))
+        };
+
+        let mut ui : Ui<Log> = Ui::new(|_, _, _| ());
+        ui.add_command("w", logger("w"));
+        ui.add_raw_command("s", logger("s"));
+
+        let run = |ui:&mut Ui<Log>, line:&str| {
+            let mut log = Vec::new();
+            ui.launch_command(&mut log, line.to_string());
+            log
+        };
+        let call = |name:&str, args:&[&str]| vec![(name.to_string(), args.iter().map(|a| a.to_string()).collect())];
+
+        assert_eq!(run(&mut ui, "w foo.png"), call("w", &["foo.png"]));
+        assert_eq!(run(&mut ui, "s/a/b/g"), call("s", &["/a/b/g"]));
+        assert_eq!(run(&mut ui, "s a b"), call("s", &["a", "b"]));
+        // only raw commands take the rest of the line
+        assert_eq!(run(&mut ui, "w! foo.png"), vec![]);
+    }
+}
@@ -0,0 +1,312 @@
+use std::str::FromStr;
+
+use crate::color::{Color, Metric};
+
+/// Pixel art upscalers. All of them only use the colors of the image, so that an upscaled indexed
+/// image keeps its palette.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Filter {
+    Nearest,
+    /// EPX/AdvMAME2x, scales by powers of 2.
+    Scale2x,
+    /// AdvMAME3x, scales by powers of 3.
+    Scale3x,
+    /// The corner rule of xBR 2x, without blending, scales by powers of 2.
+    Xbr,
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s:&str) -> Result<Self, String> {
+        match s {
+            "nearest" => Ok(Filter::Nearest),
+            "scale2x" | "epx" => Ok(Filter::Scale2x),
+            "scale3x" => Ok(Filter::Scale3x),
+            "xbr" => Ok(Filter::Xbr),
+            _ => Err(format!("unknown filter: {}", s)),
+        }
+    }
+}
+
+/// Pixels of an image, with the pixels out of it being those of the closest border.
+struct Image<'a> {
+    data:&'a [Color],
+    size:(usize, usize),
+}
+
+impl<'a> Image<'a> {
+    fn at(&self, x:usize, y:usize, (dx, dy):(isize, isize)) -> Color {
+        let (w, h) = self.size;
+        let x = (x as isize + dx).max(0).min(w as isize - 1) as usize;
+        let y = (y as isize + dy).max(0).min(h as isize - 1) as usize;
+        self.data[y * w + x]
+    }
+}
+
+/// Scale an image `factor` times, and return the scaled pixels and size. The source image is
+/// left untouched.
+pub fn scale(data:&[Color], size:(usize, usize), factor:usize, filter:Filter) -> Result<(Vec<Color>, (usize, usize)), String> {
+    let (step, name) = match filter {
+        Filter::Nearest => return Ok((nearest(data, size, factor), (size.0 * factor, size.1 * factor))),
+        Filter::Scale2x => (2, "scale2x"),
+        Filter::Scale3x => (3, "scale3x"),
+        Filter::Xbr => (2, "xbr"),
+    };
+
+    // the filters are applied as many times as needed, which only works for powers of their scale
+    let mut steps = 0;
+    let mut rest = factor;
+    while rest > 1 && rest % step == 0 {
+        rest /= step;
+        steps += 1;
+    }
+    if rest != 1 {
+        return Err(format!("{} only scales by powers of {}", name, step))
+    }
+
+    let (mut data, mut size) = (data.to_vec(), size);
+    for _ in 0..steps {
+        let image = Image { data:&data, size };
+        data = match filter {
+            Filter::Scale2x => scale2x(&image),
+            Filter::Scale3x => scale3x(&image),
+            _ => xbr(&image),
+        };
+        size = (size.0 * step, size.1 * step);
+    }
+
+    Ok((data, size))
+}
+
+fn nearest(data:&[Color], (w, h):(usize, usize), factor:usize) -> Vec<Color> {
+    let sw = w * factor;
+    (0..h * factor)
+        .flat_map(|y| (0..sw).map(move |x| data[(y / factor) * w + x / factor]))
+        .collect()
+}
+
+/// Write the `n`x`n` block of each source pixel, given row by row by `block`.
+fn by_blocks<F>(image:&Image, n:usize, block:F) -> Vec<Color>
+    where F : Fn(usize, usize) -> Vec<Color>
+{
+    let (w, h) = image.size;
+    let mut ret = vec![Color::rgba(0, 0, 0, 0); w * h * n * n];
+
+    for y in 0..h {
+        for x in 0..w {
+            for (i, c) in block(x, y).into_iter().enumerate() {
+                ret[(y * n + i / n) * w * n + x * n + i % n] = c;
+            }
+        }
+    }
+
+    ret
+}
+
+fn scale2x(image:&Image) -> Vec<Color> {
+    by_blocks(image, 2, |x, y| {
+        let p = |dx, dy| image.at(x, y, (dx, dy));
+        let (b, d, e, f, h) = (p(0, -1), p(-1, 0), p(0, 0), p(1, 0), p(0, 1));
+
+        if b != h && d != f {
+            vec![
+                if d == b { d } else { e },
+                if b == f { f } else { e },
+                if d == h { d } else { e },
+                if h == f { f } else { e },
+            ]
+        } else {
+            vec![e; 4]
+        }
+    })
+}
+
+fn scale3x(image:&Image) -> Vec<Color> {
+    by_blocks(image, 3, |x, y| {
+        let p = |dx, dy| image.at(x, y, (dx, dy));
+        let (a, b, c) = (p(-1, -1), p(0, -1), p(1, -1));
+        let (d, e, f) = (p(-1, 0), p(0, 0), p(1, 0));
+        let (g, h, i) = (p(-1, 1), p(0, 1), p(1, 1));
+
+        if b != h && d != f {
+            vec![
+                if d == b { d } else { e },
+                if (d == b && e != c) || (b == f && e != a) { b } else { e },
+                if b == f { f } else { e },
+                if (d == b && e != g) || (d == h && e != a) { d } else { e },
+                e,
+                if (b == f && e != i) || (h == f && e != c) { f } else { e },
+                if d == h { d } else { e },
+                if (d == h && e != i) || (h == f && e != g) { h } else { e },
+                if h == f { f } else { e },
+            ]
+        } else {
+            vec![e; 9]
+        }
+    })
+}
+
+/// Each corner of the 2x2 block takes the color of a neighbour when an edge crosses it, the edge
+/// being detected by comparing the color differences along both diagonals, as xBR does.
+fn xbr(image:&Image) -> Vec<Color> {
+    let dist = |a:Color, b:Color| Metric::Oklab.distance(a, b);
+
+    by_blocks(image, 2, |x, y| {
+        let e = image.at(x, y, (0, 0));
+        let mut block = vec![e; 4];
+
+        // offsets of the bottomright corner, rotated a quarter turn for each other corner
+        for (corner, rot) in [(3, 0), (2, 1), (0, 2), (1, 3)].iter() {
+            let p = |dx:isize, dy:isize| {
+                let (dx, dy) = (0..*rot).fold((dx, dy), |(dx, dy), _| (-dy, dx));
+                image.at(x, y, (dx, dy))
+            };
+            let (b, c, d, f, g, h, i) = (p(0, -1), p(1, -1), p(-1, 0), p(1, 0), p(-1, 1), p(0, 1), p(1, 1));
+            let (f4, i4, h5, i5) = (p(2, 0), p(2, 1), p(0, 2), p(1, 2));
+
+            if e == f || e == h {
+                continue
+            }
+
+            let across = dist(e, c) + dist(e, g) + dist(i, f4) + dist(i, h5) + 4.0 * dist(h, f);
+            let along = dist(h, d) + dist(h, i5) + dist(f, i4) + dist(f, b) + 4.0 * dist(e, i);
+            if across < along {
+                block[*corner] = if dist(e, f) <= dist(e, h) { f } else { h };
+            }
+        }
+
+        block
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Image drawn with `#` for white, `o` for blue and `.` for transparent pixels.
+    fn image(rows:&[&str]) -> (Vec<Color>, (usize, usize)) {
+        let data = rows
+            .iter()
+            .flat_map(|row| row.chars())
+            .map(|c| match c {
+                '#' => Color::rgb(255, 255, 255),
+                'o' => Color::rgb(0, 0, 255),
+                _ => Color::rgba(0, 0, 0, 0),
+            })
+            .collect();
+        (data, (rows[0].len(), rows.len()))
+    }
+
+    fn diagonal() -> (Vec<Color>, (usize, usize)) {
+        image(&["#...", ".#..", "..#.", "...#"])
+    }
+
+    fn check(filter:Filter, factor:usize, (data, size):(Vec<Color>, (usize, usize)), expected:&[&str]) {
+        assert_eq!(scale(&data, size, factor, filter), Ok(image(expected)), "{:?} {}x", filter, factor);
+    }
+
+    #[test]
+    fn nearest() {
+        check(Filter::Nearest, 2, diagonal(), &[
+            "##......",
+            "##......",
+            "..##....",
+            "..##....",
+            "....##..",
+            "....##..",
+            "......##",
+            "......##",
+        ]);
+        check(Filter::Nearest, 3, image(&["#o", ".#"]), &[
+            "###ooo",
+            "###ooo",
+            "###ooo",
+            "...###",
+            "...###",
+            "...###",
+        ]);
+    }
+
+    #[test]
+    fn scale2x() {
+        check(Filter::Scale2x, 2, diagonal(), &[
+            "##......",
+            "#.#.....",
+            ".###....",
+            "..###...",
+            "...###..",
+            "....###.",
+            ".....#.#",
+            "......##",
+        ]);
+        check(Filter::Scale2x, 2, image(&["###.", "#o..", "#...", "...."]), &[
+            "######..",
+            "#####...",
+            "###o....",
+            "##o.....",
+            "##......",
+            "#.......",
+            "........",
+            "........",
+        ]);
+    }
+
+    #[test]
+    fn scale3x() {
+        check(Filter::Scale3x, 3, diagonal(), &[
+            "###.........",
+            "##.#........",
+            "#..#........",
+            ".#####......",
+            "...###......",
+            "...####.....",
+            ".....####...",
+            "......###...",
+            "......#####.",
+            "........#..#",
+            "........#.##",
+            ".........###",
+        ]);
+    }
+
+    #[test]
+    fn xbr() {
+        check(Filter::Xbr, 2, diagonal(), &[
+            "##......",
+            "###.....",
+            ".##.....",
+            "...##...",
+            "...##...",
+            ".....##.",
+            ".....###",
+            "......##",
+        ]);
+        check(Filter::Xbr, 2, image(&["###.", "#o..", "#...", "...."]), &[
+            "######..",
+            "#####...",
+            "###oo...",
+            "##o.....",
+            "##o.....",
+            "#.......",
+            "........",
+            "........",
+        ]);
+    }
+
+    #[test]
+    fn powers() {
+        // scaling by a power applies the filter several times
+        for &(filter, step) in &[(Filter::Scale2x, 2), (Filter::Scale3x, 3), (Filter::Xbr, 2)] {
+            let (data, size) = diagonal();
+            let (once, once_size) = scale(&data, size, step, filter).unwrap();
+            assert_eq!(scale(&data, size, step * step, filter), scale(&once, once_size, step, filter));
+        }
+
+        let (data, size) = diagonal();
+        assert_eq!(scale(&data, size, 1, Filter::Xbr), Ok((data.clone(), size)));
+        assert_eq!(scale(&data, size, 6, Filter::Scale2x), Err(String::from("scale2x only scales by powers of 2")));
+        assert_eq!(scale(&data, size, 2, Filter::Scale3x), Err(String::from("scale3x only scales by powers of 3")));
+        assert_eq!(scale(&data, size, 3, Filter::Xbr), Err(String::from("xbr only scales by powers of 2")));
+    }
+}